@@ -0,0 +1,121 @@
+//! Drives `CodecFramed` with arbitrary byte chunks and readiness, reading,
+//! writing and flushing length-prefixed frames in arbitrary order.
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_proto_fuzz;
+
+use bytes::{BlockBuf, MutBuf};
+use tokio_core::io::FramedIo;
+use tokio_proto::{Codec, CodecFramed, FlushPolicy, ParseBuf};
+use tokio_proto_fuzz::{Input, ScriptedIo, MAX_STEPS};
+use std::io;
+
+fuzz_target!(|data: &[u8]| {
+    let input = Input::shared(data);
+
+    let (rd, wr, policy, max_read) = {
+        let mut input = input.borrow_mut();
+
+        // Small blocks, so that frames span several of them and the read
+        // buffer has to grow
+        let rd = BlockBuf::new(1 + input.byte() as usize % 8, 1 + input.byte() as usize % 16);
+        let wr = BlockBuf::default();
+
+        let policy = match input.byte() % 5 {
+            0 => FlushPolicy::OnFlush,
+            1 => FlushPolicy::Immediate,
+            2 => FlushPolicy::BlockFull,
+            3 => FlushPolicy::Bytes(input.byte() as usize),
+            _ => FlushPolicy::Frames(input.byte() as usize),
+        };
+
+        (rd, wr, policy, input.byte() as usize * 4)
+    };
+
+    let mut framed = CodecFramed::with_buffers(ScriptedIo::new(input.clone()), LengthPrefixed, rd, wr);
+    framed.set_flush_policy(policy);
+    framed.set_max_read_buf(max_read);
+
+    for _ in 0..MAX_STEPS {
+        let op = {
+            let mut input = input.borrow_mut();
+
+            if input.is_empty() {
+                break;
+            }
+
+            input.byte()
+        };
+
+        let res = match op % 3 {
+            0 => framed.read().map(|_| ()),
+            1 => {
+                let frame = {
+                    let mut input = input.borrow_mut();
+                    let len = input.byte() as usize;
+                    input.bytes(len).to_vec()
+                };
+
+                // Serialization errors leave the transport usable
+                let _ = framed.write(frame);
+                Ok(())
+            }
+            _ => framed.flush().map(|_| ()),
+        };
+
+        if res.is_err() {
+            break;
+        }
+    }
+});
+
+/// Frames are a single length byte followed by the payload
+struct LengthPrefixed;
+
+impl Codec for LengthPrefixed {
+    type Out = Vec<u8>;
+    type In = Vec<u8>;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Vec<u8>>> {
+        let mut len = [0];
+
+        if !buf.peek_slice(&mut len) {
+            return Ok(None);
+        }
+
+        let mut frame = vec![0; 1 + len[0] as usize];
+
+        if !buf.peek_slice(&mut frame) {
+            return Ok(None);
+        }
+
+        buf.drop(frame.len());
+        frame.remove(0);
+
+        Ok(Some(frame))
+    }
+
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Vec<u8>>> {
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-frame"))
+        }
+    }
+
+    fn serialize(&mut self, frame: Vec<u8>, buf: &mut BlockBuf) -> io::Result<()> {
+        if frame.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+        }
+
+        buf.write_slice(&[frame.len() as u8]);
+        buf.write_slice(&frame);
+        Ok(())
+    }
+}
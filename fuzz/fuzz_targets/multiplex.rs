@@ -0,0 +1,114 @@
+//! Drives a `multiplex::Server` with arbitrary frame sequences and transport
+//! readiness, echoing each request with its body.
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate futures;
+extern crate tokio_proto;
+extern crate tokio_proto_fuzz;
+extern crate tokio_service;
+
+use futures::{finished, Async, Poll};
+use futures::stream::{self, Receiver};
+use tokio_proto::multiplex::{self, Frame, Message, RequestId, Transport};
+use tokio_proto_fuzz::{run, Input};
+use std::io;
+
+type Body = Receiver<u8, io::Error>;
+
+fuzz_target!(|data: &[u8]| {
+    let service = tokio_service::simple_service(|req: Message<u8, Body>| {
+        finished::<_, io::Error>(req)
+    });
+
+    let peer = Peer { input: Input::new(data) };
+
+    if let Ok(server) = multiplex::Server::new(service, peer) {
+        run(server);
+    }
+});
+
+/// Transport reading frames from, and accepting writes as dictated by, the
+/// input
+struct Peer<'a> {
+    input: Input<'a>,
+}
+
+impl<'a> Transport for Peer<'a> {
+    type In = u8;
+    type BodyIn = u8;
+    type Out = Message<u8, Body>;
+    type BodyOut = u8;
+    type Error = io::Error;
+
+    fn poll_read(&mut self) -> Async<()> {
+        if self.input.ready() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    fn read(&mut self) -> Poll<Frame<Message<u8, Body>, u8, io::Error>, io::Error> {
+        if self.input.is_empty() {
+            return Ok(Async::Ready(Frame::Done));
+        }
+
+        let op = self.input.byte();
+        let id = self.request_id();
+
+        let frame = match op % 8 {
+            0 => return Ok(Async::NotReady),
+            1 => Frame::Message(id, Message::WithoutBody(self.input.byte())),
+            2 => {
+                let (tx, rx) = stream::channel();
+                Frame::MessageWithBody(id, Message::WithBody(self.input.byte(), rx), tx)
+            }
+            3 => Frame::Body(id, Some(self.input.byte())),
+            4 => Frame::Body(id, None),
+            5 => Frame::Error(id, io::Error::new(io::ErrorKind::Other, "peer error")),
+            6 => return Err(io::Error::new(io::ErrorKind::Other, "read error")),
+            _ => Frame::Done,
+        };
+
+        Ok(Async::Ready(frame))
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        if self.input.ready() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    fn write(&mut self, _: Frame<u8, u8, io::Error>) -> Poll<(), io::Error> {
+        self.complete()
+    }
+
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        self.complete()
+    }
+}
+
+impl<'a> Peer<'a> {
+    // Few distinct ids, so that frames refer to requests already in flight
+    fn request_id(&mut self) -> RequestId {
+        (self.input.byte() % 8) as RequestId
+    }
+
+    // Completes a write or flush, or not, as dictated by the input
+    fn complete(&mut self) -> Poll<(), io::Error> {
+        if self.input.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        match self.input.byte() % 3 {
+            0 => Ok(Async::NotReady),
+            1 => Err(io::Error::new(io::ErrorKind::Other, "write error")),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
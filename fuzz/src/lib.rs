@@ -0,0 +1,162 @@
+//! Helpers shared by the fuzz targets
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+
+use futures::{Async, Future};
+use futures::executor::{self, Unpark};
+use tokio_core::io::Io;
+use std::cell::RefCell;
+use std::{cmp, io};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Max number of steps a target takes before giving up
+pub const MAX_STEPS: usize = 1024;
+
+/// Fuzzer input, read as a sequence of decisions
+pub struct Input<'a> {
+    data: &'a [u8],
+}
+
+/// Input shared between the target and the objects it drives
+pub type SharedInput<'a> = Rc<RefCell<Input<'a>>>;
+
+impl<'a> Input<'a> {
+    /// Create a new `Input` reading from `data`
+    pub fn new(data: &'a [u8]) -> Input<'a> {
+        Input { data: data }
+    }
+
+    /// Create a new `Input` that can be shared
+    pub fn shared(data: &'a [u8]) -> SharedInput<'a> {
+        Rc::new(RefCell::new(Input::new(data)))
+    }
+
+    /// Returns true once all input has been read
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the next byte, or 0 once all input has been read
+    pub fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((&b, rest)) => {
+                self.data = rest;
+                b
+            }
+            None => 0,
+        }
+    }
+
+    /// Returns up to the next `n` bytes
+    pub fn bytes(&mut self, n: usize) -> &'a [u8] {
+        let n = cmp::min(n, self.data.len());
+        let (ret, rest) = self.data.split_at(n);
+        self.data = rest;
+        ret
+    }
+
+    /// Returns true unless the next byte is a multiple of 4
+    pub fn ready(&mut self) -> bool {
+        self.is_empty() || self.byte() % 4 != 0
+    }
+}
+
+/// An `Io` reading from, and accepting writes as dictated by, the input
+///
+/// Reads return chunks of the input, would-block or EOF. Writes accept some of
+/// the bytes, would-block or fail. Once the input has been read, reads return
+/// EOF and writes accept everything.
+pub struct ScriptedIo<'a> {
+    input: SharedInput<'a>,
+}
+
+impl<'a> ScriptedIo<'a> {
+    /// Create a new `ScriptedIo` driven by `input`
+    pub fn new(input: SharedInput<'a>) -> ScriptedIo<'a> {
+        ScriptedIo { input: input }
+    }
+}
+
+impl<'a> io::Read for ScriptedIo<'a> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut input = self.input.borrow_mut();
+
+        if input.is_empty() {
+            return Ok(0);
+        }
+
+        match input.byte() % 4 {
+            0 => Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
+            1 => Ok(0),
+            _ => {
+                let n = cmp::min(input.byte() as usize, dst.len());
+                let chunk = input.bytes(n);
+
+                dst[..chunk.len()].copy_from_slice(chunk);
+                Ok(chunk.len())
+            }
+        }
+    }
+}
+
+impl<'a> io::Write for ScriptedIo<'a> {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let mut input = self.input.borrow_mut();
+
+        if input.is_empty() {
+            return Ok(src.len());
+        }
+
+        match input.byte() % 4 {
+            0 => Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
+            1 => Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")),
+            _ => Ok(cmp::min(input.byte() as usize + 1, src.len())),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Io for ScriptedIo<'a> {
+    fn poll_read(&mut self) -> Async<()> {
+        if self.input.borrow_mut().ready() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        if self.input.borrow_mut().ready() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
+/// Polls `f` until it completes, fails or `MAX_STEPS` polls have been made
+///
+/// Nothing notifies the task, it is simply polled again.
+pub fn run<F: Future>(f: F) {
+    let mut task = executor::spawn(f);
+    let unpark = Arc::new(Noop);
+
+    for _ in 0..MAX_STEPS {
+        match task.poll_future(unpark.clone()) {
+            Ok(Async::NotReady) => {}
+            _ => return,
+        }
+    }
+}
+
+struct Noop;
+
+impl Unpark for Noop {
+    fn unpark(&self) {}
+}
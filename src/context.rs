@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Identifies the request currently being processed by a server
+///
+/// The dispatchers assign each connection a process-wide unique id, and each
+/// request a sequence number within its connection. Both are included in all
+/// log events the dispatchers emit for the request.
+///
+/// While a service is called, or its response future is polled, the context
+/// of the request is available from `request_context`. This allows including
+/// it in the service's own log events, or passing a trace context received
+/// from the peer on to requests made while processing the request.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    connection_id: usize,
+    request_seq: u64,
+    trace_id: Option<Arc<String>>,
+}
+
+// Source of connection ids
+static NEXT_CONNECTION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+thread_local!(static CURRENT: RefCell<Option<RequestContext>> = RefCell::new(None));
+
+/// Returns the context of the request currently being processed, if any.
+///
+/// Only returns `Some` when called from a service's `call` function, or while
+/// its response future is polled by the dispatcher.
+pub fn request_context() -> Option<RequestContext> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+impl RequestContext {
+    /// Create a new `RequestContext`
+    pub fn new(connection_id: usize, request_seq: u64, trace_id: Option<String>) -> RequestContext {
+        RequestContext {
+            connection_id: connection_id,
+            request_seq: request_seq,
+            trace_id: trace_id.map(Arc::new),
+        }
+    }
+
+    /// Returns the id of the connection the request was received on
+    pub fn connection_id(&self) -> usize {
+        self.connection_id
+    }
+
+    /// Returns the sequence number of the request on its connection, starting
+    /// at 0.
+    ///
+    /// For multiplexed protocols, this is the `RequestId` of the request.
+    pub fn request_seq(&self) -> u64 {
+        self.request_seq
+    }
+
+    /// Returns the trace context extracted from the request, if any.
+    ///
+    /// See `pipeline::Server::set_trace_extractor`.
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_ref().map(|s| &s[..])
+    }
+
+    /// Runs `f` with `self` as the current request context
+    pub fn enter<F, R>(&self, f: F) -> R
+        where F: FnOnce() -> R,
+    {
+        // Restores the previous context, even if `f` panics
+        struct Reset(Option<RequestContext>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                let prev = self.0.take();
+                CURRENT.with(|current| *current.borrow_mut() = prev);
+            }
+        }
+
+        let prev = CURRENT.with(|current| {
+            current.borrow_mut().take()
+        });
+
+        let _reset = Reset(prev);
+
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.clone()));
+
+        f()
+    }
+}
+
+/// Returns a new, process-wide unique, connection id
+pub fn next_connection_id() -> usize {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
@@ -0,0 +1,65 @@
+use futures::task::{self, Task};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Tells a live pipeline or multiplex connection to drain
+///
+/// Once drained, the connection stops reading new requests, then closes once
+/// every in-flight response has been written and flushed. If enabled with
+/// `set_goaway`, a `Frame::GoAway` is written first so that the peer can
+/// migrate to another connection.
+///
+/// Returned by the `drain_handle` functions of `pipeline::Server` and
+/// `multiplex::Server`. Connections added to a `Registry` can also be drained
+/// with `Registry::drain_connection`.
+#[derive(Clone)]
+pub struct Drain {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    draining: AtomicBool,
+    // The connection task, notified when draining starts
+    task: Mutex<Option<Task>>,
+}
+
+impl Drain {
+    /// Starts draining the connection
+    ///
+    /// Does nothing if the connection is already draining or closed.
+    pub fn drain(&self) {
+        self.inner.draining.store(true, Ordering::SeqCst);
+
+        if let Some(task) = self.inner.task.lock().unwrap().take() {
+            task.unpark();
+        }
+    }
+
+    /// Returns true once `drain` has been called
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+}
+
+/// Create a new `Drain`, not draining yet
+pub fn new() -> Drain {
+    Drain {
+        inner: Arc::new(Inner {
+            draining: AtomicBool::new(false),
+            task: Mutex::new(None),
+        }),
+    }
+}
+
+/// Returns true if the connection should drain. Otherwise, the current task
+/// is notified once it should.
+pub fn poll(drain: &Drain) -> bool {
+    if drain.is_draining() {
+        return true;
+    }
+
+    *drain.inner.task.lock().unwrap() = Some(task::park());
+
+    // `drain` may have been called before the task was stored
+    drain.is_draining()
+}
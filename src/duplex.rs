@@ -0,0 +1,212 @@
+//! An in-memory, bidirectional `Io` pair.
+
+use io::WriteVec;
+use futures::Async;
+use futures::task::{self, Task};
+use tokio_core::io::Io;
+use std::{cmp, io};
+use std::sync::{Arc, Mutex};
+
+/// Default number of bytes buffered in each direction
+const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// One end of an in-memory, bidirectional byte stream.
+///
+/// Created by `duplex`. Bytes written to one end are read from the other end,
+/// as with a socket pair. Both ends may be moved to, and used from, different
+/// tasks and threads.
+///
+/// Reading from, and writing to, a `Duplex` never blocks. When no data is
+/// available, or the buffer is full, an `ErrorKind::WouldBlock` error is
+/// returned and the current task is notified once the operation may succeed.
+/// As such, a `Duplex` must only be used from within a task.
+///
+/// Dropping one end closes the stream: the other end reads EOF once it has
+/// read all buffered bytes, and writing to it fails with
+/// `ErrorKind::BrokenPipe`.
+pub struct Duplex {
+    // Bytes written by the other end
+    rd: Arc<Mutex<Pipe>>,
+    // Bytes written by this end
+    wr: Arc<Mutex<Pipe>>,
+}
+
+// One direction of the stream
+struct Pipe {
+    buf: Vec<u8>,
+    capacity: usize,
+    // True once the reading end has been dropped
+    rd_closed: bool,
+    // True once the writing end has been dropped
+    wr_closed: bool,
+    // Task blocked on reading
+    rd_task: Option<Task>,
+    // Task blocked on writing
+    wr_task: Option<Task>,
+}
+
+/// Create a connected pair of in-memory `Io` objects.
+///
+/// Up to 64KB are buffered in each direction.
+pub fn duplex() -> (Duplex, Duplex) {
+    duplex_with_capacity(DEFAULT_CAPACITY)
+}
+
+/// Create a connected pair of in-memory `Io` objects, buffering up to
+/// `capacity` bytes in each direction.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+pub fn duplex_with_capacity(capacity: usize) -> (Duplex, Duplex) {
+    assert!(capacity > 0, "capacity must be greater than 0");
+
+    let a = Arc::new(Mutex::new(Pipe::new(capacity)));
+    let b = Arc::new(Mutex::new(Pipe::new(capacity)));
+
+    let one = Duplex {
+        rd: a.clone(),
+        wr: b.clone(),
+    };
+
+    let two = Duplex {
+        rd: b,
+        wr: a,
+    };
+
+    (one, two)
+}
+
+impl io::Read for Duplex {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.rd.lock().unwrap();
+
+        if pipe.buf.is_empty() {
+            if pipe.wr_closed || dst.is_empty() {
+                return Ok(0);
+            }
+
+            pipe.rd_task = Some(task::park());
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "duplex not readable"));
+        }
+
+        let n = cmp::min(dst.len(), pipe.buf.len());
+        dst[..n].copy_from_slice(&pipe.buf[..n]);
+        pipe.buf.drain(..n);
+
+        // Space was freed up
+        if let Some(task) = pipe.wr_task.take() {
+            task.unpark();
+        }
+
+        Ok(n)
+    }
+}
+
+impl io::Write for Duplex {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.write_vec(&[src])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteVec for Duplex {
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let mut pipe = self.wr.lock().unwrap();
+
+        if pipe.rd_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "duplex closed"));
+        }
+
+        let len = bufs.iter().fold(0, |n, buf| n + buf.len());
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        if pipe.buf.len() == pipe.capacity {
+            pipe.wr_task = Some(task::park());
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "duplex not writable"));
+        }
+
+        let mut n = 0;
+
+        for buf in bufs {
+            let rem = pipe.capacity - pipe.buf.len();
+            let m = cmp::min(rem, buf.len());
+
+            pipe.buf.extend_from_slice(&buf[..m]);
+            n += m;
+
+            if m < buf.len() {
+                break;
+            }
+        }
+
+        if let Some(task) = pipe.rd_task.take() {
+            task.unpark();
+        }
+
+        Ok(n)
+    }
+}
+
+impl Io for Duplex {
+    fn poll_read(&mut self) -> Async<()> {
+        let mut pipe = self.rd.lock().unwrap();
+
+        if !pipe.buf.is_empty() || pipe.wr_closed {
+            Async::Ready(())
+        } else {
+            pipe.rd_task = Some(task::park());
+            Async::NotReady
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        let mut pipe = self.wr.lock().unwrap();
+
+        if pipe.buf.len() < pipe.capacity || pipe.rd_closed {
+            Async::Ready(())
+        } else {
+            pipe.wr_task = Some(task::park());
+            Async::NotReady
+        }
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        if let Ok(mut pipe) = self.rd.lock() {
+            pipe.rd_closed = true;
+
+            if let Some(task) = pipe.wr_task.take() {
+                task.unpark();
+            }
+        }
+
+        if let Ok(mut pipe) = self.wr.lock() {
+            pipe.wr_closed = true;
+
+            if let Some(task) = pipe.rd_task.take() {
+                task.unpark();
+            }
+        }
+    }
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Pipe {
+        Pipe {
+            buf: Vec::new(),
+            capacity: capacity,
+            rd_closed: false,
+            wr_closed: false,
+            rd_task: None,
+            wr_task: None,
+        }
+    }
+}
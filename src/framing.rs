@@ -1,39 +1,151 @@
 #![allow(warnings)]
 
-use futures::{Async, Poll};
-use tokio_core::io::{Io, FramedIo};
-use io::{TryRead, TryWrite};
-use bytes::{alloc, MutBuf, BlockBuf, Source};
-use std::io;
-
-/// FramedIo handling frame encoding and decoding.
-pub struct Framed<T, P, S> {
+use futures::{Async, AsyncSink, Poll, Sink, StartSend};
+use futures::stream::Stream;
+use tokio_core::io::{Io, FramedIo, ReadHalf, WriteHalf};
+use io::{TryRead, TryWrite, WriteVec};
+use bytes::{alloc, Buf, MutBuf, BlockBuf, Bytes, Source};
+use smallvec::SmallVec;
+use std::{cmp, io};
+use std::sync::{Arc, Mutex};
+
+/// The max number of write buffer blocks handed to a single vectored write
+const MAX_WRITE_BUFS: usize = 16;
+
+/// Block size used when growing or shrinking the read buffer
+const READ_BLOCK_SIZE: usize = 8 * 1024;
+
+/// Default max size the read buffer may grow to
+const DEFAULT_MAX_READ_BUF: usize = 4 * 1024 * 1024;
+
+/// A `CodecFramed` encoding and decoding frames with a separate `Parse` and
+/// `Serialize`.
+///
+/// Adds `Framed::new`, which takes the two halves of the codec. All other
+/// functionality, including `split`, is documented on `CodecFramed`.
+pub type Framed<T, P, S> = CodecFramed<T, (P, S)>;
+
+/// FramedIo handling frame encoding and decoding with a `Codec`.
+///
+/// This is the entry point for framed transports: create one with
+/// `CodecFramed::with_codec` when encoding and decoding are done by a single
+/// `Codec`, or with `Framed::new` when they are done by a `Parse` and a
+/// `Serialize`.
+pub struct CodecFramed<T, C> {
     upstream: T,
-    parse: P,
-    serialize: S,
+    codec: C,
     // Set to true until `parse` returns `None`
     is_readable: bool,
     // Read buffer
-    rd: BlockBuf,
+    rd: ReadBuf,
     // Write buffer
     wr: BlockBuf,
+    // When to write buffered frames to the upstream
+    flush_policy: FlushPolicy,
+    // Number of frames serialized since the write buffer was last drained
+    buffered_frames: usize,
+    // Error encountered while flushing from `write`, returned by `flush`
+    flush_err: Option<io::Error>,
+    // Writes buffered blocks to the upstream
+    write_bufs: WriteBufs<T>,
+}
+
+// Writes a list of buffers to the upstream, returning how many bytes were
+// written
+type WriteBufs<T> = fn(&mut T, &[&[u8]]) -> io::Result<usize>;
+
+/// Determines when frames written to a `CodecFramed` transport are written to
+/// the upstream `Io`.
+///
+/// Buffering frames reduces the number of syscalls made, at the cost of
+/// latency. Regardless of the policy, `flush` writes out all buffered frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only write frames when the transport is flushed. This is the default.
+    OnFlush,
+    /// Write each frame as soon as it has been serialized.
+    Immediate,
+    /// Write as soon as a block of the write buffer fills up.
+    BlockFull,
+    /// Write once at least this many bytes are buffered.
+    Bytes(usize),
+    /// Write once at least this many frames are buffered.
+    Frames(usize),
+}
+
+// Read buffer that grows when a partial frame fills it and shrinks back once
+// it has been drained.
+struct ReadBuf {
+    buf: BlockBuf,
+    // Capacity to shrink back to
+    initial_capacity: usize,
+    // Capacity the buffer may grow to
+    max_capacity: usize,
 }
 
 /// Parses frames out of a `BlockBuf`
+///
+/// # Zero-copy parsing
+///
+/// `ParseBuf::take_bytes` and `ParseBuf::take_until` remove a frame's payload
+/// from the read buffer as `Bytes` that share the buffer's reference-counted
+/// memory instead of copying it, so frames may hold on to their payload
+/// without a copy. The other `ParseBuf` helpers locate frame boundaries
+/// without calling `BlockBuf::compact`, which copies the buffered data into a
+/// single block.
 pub trait Parse {
 
     /// Parse result
     type Out;
 
     /// Optionally parse a frame from the given buffer.
-    fn parse(&mut self, buf: &mut BlockBuf) -> Option<Self::Out>;
+    ///
+    /// Returns `Ok(None)` when the buffer does not yet hold a complete frame.
+    /// An error indicates that the buffer contains malformed input, in which
+    /// case the error is returned from `CodecFramed::read` and the connection
+    /// should be closed.
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>>;
 
     /// Called when there are no more inbound bytes
-    fn done(&mut self, buf: &mut BlockBuf) -> Option<Self::Out> {
-        None
+    ///
+    /// An error may be returned if the remaining bytes do not form a valid
+    /// frame, for example when the peer closed the connection mid-frame.
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        Ok(None)
     }
 }
 
+/// Zero-copy helpers for parsing frames out of a `BlockBuf`
+///
+/// None of these functions compact the buffer, they operate across blocks.
+pub trait ParseBuf {
+
+    /// Returns the position of the first occurrence of `byte` in the buffer.
+    fn find_byte(&self, byte: u8) -> Option<usize>;
+
+    /// Copies the first `dst.len()` bytes of the buffer into `dst` without
+    /// consuming them.
+    ///
+    /// Returns false, leaving `dst` in an unspecified state, if the buffer
+    /// holds fewer than `dst.len()` bytes. This is intended for reading
+    /// fixed-size frame headers.
+    fn peek_slice(&self, dst: &mut [u8]) -> bool;
+
+    /// Removes the first `n` bytes of the buffer, returning them as `Bytes`
+    /// that share the buffer's memory.
+    ///
+    /// Returns `None`, consuming nothing, if the buffer holds fewer than `n`
+    /// bytes.
+    fn take_bytes(&mut self, n: usize) -> Option<Bytes>;
+
+    /// Removes the bytes up to and including the first occurrence of `byte`,
+    /// returning the bytes preceding it as `Bytes` that share the buffer's
+    /// memory.
+    ///
+    /// Returns `None`, consuming nothing, if `byte` is not in the buffer.
+    fn take_until(&mut self, byte: u8) -> Option<Bytes>;
+}
+
 /// Serialize frames into a `BlockBuf`
 pub trait Serialize {
 
@@ -41,10 +153,65 @@ pub trait Serialize {
     type In;
 
     /// Serialize the frame into the `BlockBuf`
-    fn serialize(&mut self, msg: Self::In, buf: &mut BlockBuf);
+    ///
+    /// An error indicates that the message cannot be represented on the wire.
+    /// In that case, anything already written to `buf` for this message is
+    /// discarded, so that the error only affects this message and the
+    /// transport remains usable.
+    fn serialize(&mut self, msg: Self::In, buf: &mut BlockBuf) -> io::Result<()>;
+}
+
+/// Parses frames out of, and serializes frames into, a `BlockBuf`
+///
+/// `Codec` is implemented for any `(Parse, Serialize)` pair. Implement it
+/// directly when encoding and decoding need to share state.
+pub trait Codec {
+
+    /// Parse result
+    type Out;
+
+    /// Type to serialize
+    type In;
+
+    /// Optionally parse a frame from the given buffer.
+    ///
+    /// See `Parse::parse` for details.
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>>;
+
+    /// Called when there are no more inbound bytes
+    ///
+    /// See `Parse::done` for details.
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        Ok(None)
+    }
+
+    /// Serialize the frame into the `BlockBuf`
+    ///
+    /// See `Serialize::serialize` for details.
+    fn serialize(&mut self, msg: Self::In, buf: &mut BlockBuf) -> io::Result<()>;
+}
+
+impl<P, S> Codec for (P, S)
+    where P: Parse,
+          S: Serialize,
+{
+    type Out = P::Out;
+    type In = S::In;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<P::Out>> {
+        Parse::parse(&mut self.0, buf)
+    }
+
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<P::Out>> {
+        Parse::done(&mut self.0, buf)
+    }
+
+    fn serialize(&mut self, msg: S::In, buf: &mut BlockBuf) -> io::Result<()> {
+        Serialize::serialize(&mut self.1, msg, buf)
+    }
 }
 
-impl<T, P, S> Framed<T, P, S>
+impl<T, P, S> CodecFramed<T, (P, S)>
     where T: Io,
           P: Parse,
           S: Serialize,
@@ -54,27 +221,111 @@ impl<T, P, S> Framed<T, P, S>
                parse: P,
                serialize: S,
                rd: BlockBuf,
-               wr: BlockBuf) -> Framed<T, P, S> {
+               wr: BlockBuf) -> CodecFramed<T, (P, S)> {
+        CodecFramed::with_buffers(upstream, (parse, serialize), rd, wr)
+    }
+}
+
+impl<T, C> CodecFramed<T, C>
+    where T: Io,
+          C: Codec,
+{
+    /// Create a new `CodecFramed` using `codec` for both directions and
+    /// default read and write buffers.
+    pub fn with_codec(upstream: T, codec: C) -> CodecFramed<T, C> {
+        CodecFramed::with_buffers(upstream, codec, BlockBuf::default(), BlockBuf::default())
+    }
+
+    /// Create a new `CodecFramed` using `codec` for both directions and the
+    /// given read and write buffers.
+    pub fn with_buffers(upstream: T,
+                        codec: C,
+                        rd: BlockBuf,
+                        wr: BlockBuf) -> CodecFramed<T, C> {
 
         trace!("creating new framed transport");
-        Framed {
+        CodecFramed {
             upstream: upstream,
-            parse: parse,
-            serialize: serialize,
+            codec: codec,
             is_readable: false,
-            rd: rd,
+            rd: ReadBuf::new(rd),
             wr: wr,
+            flush_policy: FlushPolicy::default(),
+            buffered_frames: 0,
+            flush_err: None,
+            write_bufs: write_first::<T>,
         }
     }
+
+    /// Sets when frames passed to `write` are written to the upstream `Io`.
+    ///
+    /// See `FlushPolicy` for the available policies. Defaults to
+    /// `FlushPolicy::OnFlush`.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    /// Sets the max size, in bytes, that the read buffer may grow to.
+    ///
+    /// When a partially received frame fills the read buffer, the buffer is
+    /// grown, up to this size. Once the buffer has been drained, it shrinks
+    /// back to its initial size. If a frame does not fit in a buffer of this
+    /// size, `read` returns an error. Defaults to 4MB.
+    pub fn set_max_read_buf(&mut self, max: usize) {
+        self.rd.set_max_capacity(max);
+    }
+
+    /// Returns a reference to the upstream `Io`.
+    pub fn get_ref(&self) -> &T {
+        &self.upstream
+    }
+
+    /// Returns a mutable reference to the upstream `Io`.
+    ///
+    /// Care should be taken not to read from or write to the `Io` directly,
+    /// as that would corrupt the frame stream.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.upstream
+    }
+
+    /// Consumes the transport, returning the upstream `Io`, the codec and the
+    /// read buffer.
+    ///
+    /// The read buffer holds any bytes that were received but not yet parsed.
+    /// Passing it to `CodecFramed::with_buffers` allows switching protocols in
+    /// the middle of a connection without losing data.
+    ///
+    /// The write buffer is discarded, so `flush` should return `Ready` before
+    /// calling this function.
+    pub fn into_parts(self) -> (T, C, BlockBuf) {
+        (self.upstream, self.codec, self.rd.buf)
+    }
 }
 
-impl<T, P, S> FramedIo for Framed<T, P, S>
+impl<T, C> CodecFramed<T, C>
+    where T: Io + WriteVec,
+          C: Codec,
+{
+    /// Sets whether the blocks of the write buffer are written to the
+    /// upstream `Io` with a single vectored write.
+    ///
+    /// When disabled, blocks are written one at a time. Defaults to disabled.
+    /// See `WriteVec` for more details.
+    pub fn set_vectored_writes(&mut self, enabled: bool) {
+        if enabled {
+            self.write_bufs = T::write_vec;
+        } else {
+            self.write_bufs = write_first::<T>;
+        }
+    }
+}
+
+impl<T, C> FramedIo for CodecFramed<T, C>
     where T: Io,
-          P: Parse,
-          S: Serialize,
+          C: Codec,
 {
-    type In = S::In;
-    type Out = P::Out;
+    type In = C::In;
+    type Out = C::Out;
 
     fn poll_read(&mut self) -> Async<()> {
         if self.is_readable || self.upstream.poll_read().is_ready() {
@@ -85,40 +336,21 @@ impl<T, P, S> FramedIo for Framed<T, P, S>
     }
 
     fn read(&mut self) -> Poll<Self::Out, io::Error> {
-        loop {
-            // If the read buffer has any pending data, then it could be
-            // possible that `parse` will return a new frame. We leave it to
-            // the parser to optimize detecting that more data is required.
-            if !self.rd.is_empty()  {
-                trace!("read buffer has data");
-                if let Some(frame) = self.parse.parse(&mut self.rd) {
-                    trace!("frame parsed from buffer");
-                    self.is_readable = true;
-                    return Ok(Async::Ready(frame));
-                }
+        let codec = &mut self.codec;
 
-                self.is_readable = false;
+        let frame = try!(read_frame(&mut self.upstream, &mut self.rd, &mut self.is_readable, |buf, eof| {
+            if eof {
+                codec.done(buf)
+            } else {
+                codec.parse(buf)
             }
+        }));
 
-            assert!(self.rd.remaining() > 0);
-
-            // Otherwise, try to read more data and try again
-            match try!(self.upstream.try_read_buf(&mut self.rd)) {
-                Async::Ready(0) => {
-                    trace!("read 0 bytes");
-
-                    if let Some(v) = self.parse.done(&mut self.rd) {
-                        return Ok(Async::Ready(v));
-                    }
-
-                    return Ok(Async::NotReady);
-                }
-                Async::Ready(_) => {}
-                Async::NotReady => {
-                    trace!("upstream Transport::read returned would-block");
-                    return Ok(Async::NotReady);
-                }
-            }
+        match frame {
+            Async::Ready(Some(frame)) => Ok(Async::Ready(frame)),
+            // Upstream is done and no final frame was parsed
+            Async::Ready(None) => Ok(Async::NotReady),
+            Async::NotReady => Ok(Async::NotReady),
         }
     }
 
@@ -136,38 +368,572 @@ impl<T, P, S> FramedIo for Framed<T, P, S>
         }
 
         // Serialize the msg
-        self.serialize.serialize(msg, &mut self.wr);
+        let codec = &mut self.codec;
+        try!(serialize_frame(&mut self.wr, |wr| codec.serialize(msg, wr)));
 
-        // Writing to the socket until flush. This allows buffering up more
-        // data and reducing the number of syscalls made.
-        //
-        // TODO: A better solution may be to wait until a block in the
-        // `BlockBuf` is full and then flush that block immediately.
-        Ok(Async::NotReady)
+        self.buffered_frames += 1;
+
+        if !self.flush_policy.is_due(&mut self.wr, self.buffered_frames) {
+            // Hold off writing to the socket until flush. This allows
+            // buffering up more data and reducing the number of syscalls made.
+            return Ok(Async::NotReady);
+        }
+
+        // An error returned from `write` only fails the frame being written,
+        // so I/O errors are held on to and returned from the next `flush`.
+        match self.flush() {
+            Ok(async) => Ok(async),
+            Err(e) => {
+                debug!("failed to flush frames; err={:?}", e);
+                self.flush_err = Some(e);
+                Ok(Async::NotReady)
+            }
+        }
     }
 
     fn flush(&mut self) -> Poll<(), io::Error> {
-        // Try flushing the underlying IO
-        let _ = try!(self.upstream.try_flush());
+        if let Some(e) = self.flush_err.take() {
+            return Err(e);
+        }
+
+        let res = try!(flush_buf(&mut self.upstream, &mut self.wr, self.write_bufs));
+
+        if res.is_ready() {
+            self.buffered_frames = 0;
+        }
+
+        Ok(res)
+    }
+}
+
+/*
+ *
+ * ===== FlushPolicy =====
+ *
+ */
+
+impl FlushPolicy {
+    // Returns true if the buffered frames should be written out now
+    fn is_due(&self, wr: &mut BlockBuf, frames: usize) -> bool {
+        match *self {
+            FlushPolicy::OnFlush => false,
+            FlushPolicy::Immediate => true,
+            // Frames only spill over into a second block once the first one
+            // is full
+            FlushPolicy::BlockFull => !wr.is_compact(),
+            FlushPolicy::Bytes(n) => wr.len() >= n,
+            FlushPolicy::Frames(n) => frames >= n,
+        }
+    }
+}
+
+impl Default for FlushPolicy {
+    fn default() -> FlushPolicy {
+        FlushPolicy::OnFlush
+    }
+}
+
+/*
+ *
+ * ===== ParseBuf =====
+ *
+ */
+
+impl ParseBuf for BlockBuf {
+    fn find_byte(&self, byte: u8) -> Option<usize> {
+        let mut cursor = self.buf();
+        let mut pos = 0;
+
+        while cursor.has_remaining() {
+            let n = {
+                let bytes = cursor.bytes();
+
+                if let Some(i) = bytes.iter().position(|&b| b == byte) {
+                    return Some(pos + i);
+                }
+
+                bytes.len()
+            };
+
+            pos += n;
+            cursor.advance(n);
+        }
+
+        None
+    }
+
+    fn peek_slice(&self, dst: &mut [u8]) -> bool {
+        if self.len() < dst.len() {
+            return false;
+        }
+
+        let mut cursor = self.buf();
+        let mut pos = 0;
+
+        while pos < dst.len() {
+            let n = {
+                let bytes = cursor.bytes();
+                let n = cmp::min(bytes.len(), dst.len() - pos);
+
+                dst[pos..pos + n].copy_from_slice(&bytes[..n]);
+                n
+            };
+
+            pos += n;
+            cursor.advance(n);
+        }
+
+        true
+    }
+
+    fn take_bytes(&mut self, n: usize) -> Option<Bytes> {
+        if self.len() < n {
+            return None;
+        }
+
+        Some(self.shift(n))
+    }
+
+    fn take_until(&mut self, byte: u8) -> Option<Bytes> {
+        let n = match self.find_byte(byte) {
+            Some(n) => n,
+            None => return None,
+        };
+
+        let bytes = self.shift(n);
+
+        // Drop the delimiter
+        self.drop(1);
+
+        Some(bytes)
+    }
+}
 
-        trace!("flushing framed transport");
+/*
+ *
+ * ===== Split =====
+ *
+ */
 
-        loop {
-            if self.wr.is_empty() {
-                trace!("framed transport flushed");
-                return Ok(Async::Ready(()));
+/// The read half of a `CodecFramed` transport, yielding parsed frames as a
+/// `Stream`.
+///
+/// Created by `CodecFramed::split`.
+pub struct FramedRead<T, C> {
+    upstream: ReadHalf<T>,
+    codec: Arc<Mutex<C>>,
+    // Set to true until `parse` returns `None`
+    is_readable: bool,
+    // Read buffer
+    rd: ReadBuf,
+}
+
+/// The write half of a `CodecFramed` transport, serializing frames given to it
+/// as a `Sink`.
+///
+/// As with `CodecFramed::write`, an error returned from `start_send` only
+/// fails the frame being sent, which is not written, and the sink remains
+/// usable. I/O errors are returned from `poll_complete`.
+///
+/// Created by `CodecFramed::split`.
+pub struct FramedWrite<T, C> {
+    upstream: WriteHalf<T>,
+    codec: Arc<Mutex<C>>,
+    // Write buffer
+    wr: BlockBuf,
+    // When to write buffered frames to the upstream
+    flush_policy: FlushPolicy,
+    // Number of frames serialized since the write buffer was last drained
+    buffered_frames: usize,
+    // Error encountered while flushing from `start_send`, returned by
+    // `poll_complete`
+    flush_err: Option<io::Error>,
+}
+
+impl<T, C> CodecFramed<T, C>
+    where T: Io,
+          C: Codec,
+{
+    /// Split the transport into a read half and a write half which can be
+    /// used from different tasks concurrently.
+    ///
+    /// Both halves share the upstream `Io` and the codec, which is locked
+    /// while a frame is parsed or serialized, so that a `Codec` keeping state
+    /// across both directions sees every frame. Any bytes buffered in the read or
+    /// write buffer are carried over to the respective half, as are the flush
+    /// policy and any error held on to from a failed flush, which is returned
+    /// by the write half.
+    ///
+    /// The write half only has access to the upstream through a `WriteHalf`,
+    /// so it writes blocks one at a time regardless of
+    /// `set_vectored_writes`.
+    pub fn split(self) -> (FramedRead<T, C>, FramedWrite<T, C>) {
+        let CodecFramed {
+            upstream,
+            codec,
+            is_readable,
+            rd,
+            wr,
+            flush_policy,
+            buffered_frames,
+            flush_err,
+            write_bufs: _,
+        } = self;
+        let (read_half, write_half) = upstream.split();
+        let codec = Arc::new(Mutex::new(codec));
+
+        let read = FramedRead {
+            upstream: read_half,
+            codec: codec.clone(),
+            is_readable: is_readable,
+            rd: rd,
+        };
+
+        let write = FramedWrite {
+            upstream: write_half,
+            codec: codec,
+            wr: wr,
+            flush_policy: flush_policy,
+            buffered_frames: buffered_frames,
+            flush_err: flush_err,
+        };
+
+        (read, write)
+    }
+}
+
+impl<T, C> Stream for FramedRead<T, C>
+    where T: Io,
+          C: Codec,
+{
+    type Item = C::Out;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<C::Out>, io::Error> {
+        let codec = &self.codec;
+
+        read_frame(&mut self.upstream, &mut self.rd, &mut self.is_readable, |buf, eof| {
+            let mut codec = codec.lock().unwrap();
+
+            if eof {
+                codec.done(buf)
+            } else {
+                codec.parse(buf)
             }
+        })
+    }
+}
+
+impl<T, C> Sink for FramedWrite<T, C>
+    where T: Io,
+          C: Codec,
+{
+    type SinkItem = C::In;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, msg: C::In) -> StartSend<C::In, io::Error> {
+        {
+            let mut codec = self.codec.lock().unwrap();
+            try!(serialize_frame(&mut self.wr, |wr| codec.serialize(msg, wr)));
+        }
+
+        self.buffered_frames += 1;
+
+        // As with `CodecFramed`, buffer until the flush policy says otherwise
+        // or the sink is flushed
+        if !self.flush_policy.is_due(&mut self.wr, self.buffered_frames) {
+            return Ok(AsyncSink::Ready);
+        }
+
+        // The frame was accepted, so I/O errors are held on to and returned
+        // from the next `poll_complete`
+        if let Err(e) = self.poll_complete() {
+            debug!("failed to flush frames; err={:?}", e);
+            self.flush_err = Some(e);
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if let Some(e) = self.flush_err.take() {
+            return Err(e);
+        }
+
+        // The halves share the upstream, so blocks are written one at a time
+        let res = try!(flush_buf(&mut self.upstream, &mut self.wr, write_first));
+
+        if res.is_ready() {
+            self.buffered_frames = 0;
+        }
+
+        Ok(res)
+    }
+}
+
+/*
+ *
+ * ===== ReadBuf =====
+ *
+ */
+
+impl ReadBuf {
+    fn new(buf: BlockBuf) -> ReadBuf {
+        let capacity = capacity(&buf);
+
+        ReadBuf {
+            buf: buf,
+            initial_capacity: capacity,
+            max_capacity: cmp::max(capacity, DEFAULT_MAX_READ_BUF),
+        }
+    }
+
+    fn set_max_capacity(&mut self, max: usize) {
+        self.max_capacity = cmp::max(max, self.initial_capacity);
+    }
+
+    fn grow(&mut self) -> io::Result<()> {
+        let current = capacity(&self.buf);
+
+        if current >= self.max_capacity {
+            debug!("frame exceeds max read buffer size; max={}", self.max_capacity);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame exceeds max read buffer size"));
+        }
+
+        let new_capacity = cmp::min(cmp::max(current, 1) * 2, self.max_capacity);
+        trace!("growing read buffer; from={}; to={}", current, new_capacity);
+
+        let mut buf = block_buf(new_capacity);
+
+        // Move the partial frame over to the new buffer
+        {
+            let mut cursor = self.buf.buf();
+
+            while cursor.has_remaining() {
+                let n = {
+                    let bytes = cursor.bytes();
+                    buf.write_slice(bytes);
+                    bytes.len()
+                };
+
+                cursor.advance(n);
+            }
+        }
+
+        self.buf = buf;
+        Ok(())
+    }
 
-            trace!("writing; remaining={:?}", self.wr.len());
+    // Releases memory acquired by `grow` once the buffer is drained
+    fn shrink(&mut self) {
+        if !self.buf.is_empty() || capacity(&self.buf) <= self.initial_capacity {
+            return;
+        }
+
+        trace!("shrinking read buffer; to={}", self.initial_capacity);
+        self.buf = block_buf(self.initial_capacity);
+
+        // The block size may have rounded the capacity up
+        self.initial_capacity = capacity(&self.buf);
+    }
+}
+
+/*
+ *
+ * ===== Helpers =====
+ *
+ */
+
+// Returns the total number of bytes the buffer can hold
+fn capacity(buf: &BlockBuf) -> usize {
+    buf.len() + buf.remaining()
+}
+
+// Returns a new `BlockBuf` able to hold at least `capacity` bytes
+fn block_buf(capacity: usize) -> BlockBuf {
+    let block_size = cmp::max(cmp::min(capacity, READ_BLOCK_SIZE), 1);
+    let blocks = (capacity + block_size - 1) / block_size;
+
+    BlockBuf::new(blocks, block_size)
+}
+
+// Serializes a frame into `wr`. If serializing fails, any bytes written for
+// the frame are discarded so that the following frames are not corrupted.
+fn serialize_frame<F>(wr: &mut BlockBuf, serialize: F) -> io::Result<()>
+    where F: FnOnce(&mut BlockBuf) -> io::Result<()>,
+{
+    let len = wr.len();
+
+    if let Err(e) = serialize(wr) {
+        debug!("failed to serialize frame; err={:?}", e);
+        truncate(wr, len);
+        return Err(e);
+    }
+
+    Ok(())
+}
 
-            match self.upstream.try_write_buf(&mut self.wr.buf()) {
-                Ok(Async::Ready(n)) => self.wr.drop(n),
-                Ok(Async::NotReady) => return Ok(Async::NotReady),
+// Drops the bytes of `buf` past the first `len` ones
+fn truncate(buf: &mut BlockBuf, len: usize) {
+    if buf.len() <= len {
+        return;
+    }
+
+    // `BlockBuf` can only drop bytes from the front, so the bytes to keep are
+    // copied out and written back. This only happens when serializing fails.
+    let mut head = Vec::with_capacity(len);
+
+    {
+        let mut cursor = buf.buf();
+
+        while head.len() < len {
+            let n = {
+                let bytes = cursor.bytes();
+                let n = cmp::min(bytes.len(), len - head.len());
+
+                head.extend_from_slice(&bytes[..n]);
+                n
+            };
+
+            cursor.advance(n);
+        }
+    }
+
+    let n = buf.len();
+    buf.drop(n);
+    buf.write_slice(&head);
+}
+
+// Reads from `upstream` into `rd` until `decode` returns a frame. `decode` is
+// called with `eof` set once `upstream` has no more bytes, in which case
+// `Ready(None)` is returned if no final frame could be decoded.
+fn read_frame<R, F, O>(upstream: &mut R,
+                       rd: &mut ReadBuf,
+                       is_readable: &mut bool,
+                       mut decode: F) -> Poll<Option<O>, io::Error>
+    where R: io::Read,
+          F: FnMut(&mut BlockBuf, bool) -> io::Result<Option<O>>,
+{
+    loop {
+        // If the read buffer has any pending data, then it could be
+        // possible that `parse` will return a new frame. We leave it to
+        // the parser to optimize detecting that more data is required.
+        if !rd.buf.is_empty()  {
+            trace!("read buffer has data");
+            let frame = match decode(&mut rd.buf, false) {
+                Ok(frame) => frame,
                 Err(e) => {
-                    trace!("framed transport flush error; err={:?}", e);
+                    debug!("failed to parse frame; err={:?}", e);
                     return Err(e);
                 }
+            };
+
+            if let Some(frame) = frame {
+                trace!("frame parsed from buffer");
+                *is_readable = true;
+                rd.shrink();
+                return Ok(Async::Ready(Some(frame)));
             }
+
+            *is_readable = false;
+        }
+
+        if rd.buf.remaining() == 0 {
+            // The buffer is full, but does not contain a complete frame
+            try!(rd.grow());
         }
+
+        // Otherwise, try to read more data and try again
+        match try!(upstream.try_read_buf(&mut rd.buf)) {
+            Async::Ready(0) => {
+                trace!("read 0 bytes");
+
+                return match decode(&mut rd.buf, true) {
+                    Ok(frame) => Ok(Async::Ready(frame)),
+                    Err(e) => {
+                        debug!("failed to parse final frame; err={:?}", e);
+                        Err(e)
+                    }
+                };
+            }
+            Async::Ready(_) => {}
+            Async::NotReady => {
+                trace!("upstream Transport::read returned would-block");
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+// Writes the contents of `wr` to `upstream`, handing up to `MAX_WRITE_BUFS`
+// blocks at a time to `write_bufs`
+fn flush_buf<W>(upstream: &mut W,
+                wr: &mut BlockBuf,
+                write_bufs: WriteBufs<W>) -> Poll<(), io::Error>
+    where W: io::Write,
+{
+    // Try flushing the underlying IO
+    let _ = try!(upstream.try_flush());
+
+    trace!("flushing framed transport");
+
+    loop {
+        if wr.is_empty() {
+            trace!("framed transport flushed");
+            return Ok(Async::Ready(()));
+        }
+
+        trace!("writing; remaining={:?}", wr.len());
+
+        let res = {
+            // A cursor positioned at the start of each pending block. The
+            // slices handed to the upstream borrow from the cursors, so they
+            // are all kept alive until the write completes.
+            let mut cursors: SmallVec<[_; MAX_WRITE_BUFS]> = SmallVec::new();
+            let mut pos = 0;
+
+            while cursors.len() < MAX_WRITE_BUFS && pos < wr.len() {
+                let mut cursor = wr.buf();
+                cursor.advance(pos);
+
+                let n = cursor.bytes().len();
+
+                if n == 0 {
+                    break;
+                }
+
+                pos += n;
+                cursors.push(cursor);
+            }
+
+            let mut bufs: SmallVec<[&[u8]; MAX_WRITE_BUFS]> = SmallVec::new();
+
+            for cursor in cursors.iter() {
+                bufs.push(cursor.bytes());
+            }
+
+            match write_bufs(upstream, &bufs) {
+                Ok(n) => Ok(Async::Ready(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+                Err(e) => Err(e),
+            }
+        };
+
+        match res {
+            Ok(Async::Ready(n)) => wr.drop(n),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => {
+                trace!("framed transport flush error; err={:?}", e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Writes the first non-empty buffer, for upstreams without vectored writes
+fn write_first<W: io::Write>(upstream: &mut W, bufs: &[&[u8]]) -> io::Result<usize> {
+    match bufs.iter().find(|buf| !buf.is_empty()) {
+        Some(buf) => upstream.write(buf),
+        None => Ok(0),
     }
 }
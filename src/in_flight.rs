@@ -0,0 +1,132 @@
+use futures::task::{self, EventSet, UnparkEvent};
+use slab::Slab;
+use std::{cmp, mem};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Storage for the in-flight requests of a server dispatch, tracking which of
+/// them need to be polled.
+///
+/// Entries are stored in a slab and identified by their slot. Each entry's
+/// response future is polled with an `UnparkEvent` carrying its slot, so that
+/// when the task is notified only the entries whose future triggered the
+/// notification are polled again, instead of every in-flight future on every
+/// tick. Looking up a notified entry is an index into the slab.
+pub struct InFlightSet<T> {
+    entries: Slab<Entry<T>>,
+    // Slots of the entries notified since last polled
+    ready: Arc<Mutex<Vec<usize>>>,
+    // Swapped with the ready slots while polling, to reuse the allocation
+    scratch: Vec<usize>,
+}
+
+struct Entry<T> {
+    val: T,
+    notify: Arc<Notify>,
+}
+
+// Queues the slot of an entry when its future notifies the task
+struct Notify {
+    slot: usize,
+    // True while the slot is in the ready list, so that a future notifying
+    // the task repeatedly before it is polled is only queued once
+    queued: AtomicBool,
+    ready: Arc<Mutex<Vec<usize>>>,
+}
+
+/// The number of slots allocated up front, and by which the slab grows at
+/// least.
+const INITIAL_CAPACITY: usize = 32;
+
+impl<T> InFlightSet<T> {
+    /// Create a new, empty, `InFlightSet`
+    pub fn new() -> InFlightSet<T> {
+        InFlightSet {
+            entries: Slab::with_capacity(INITIAL_CAPACITY),
+            ready: Arc::new(Mutex::new(vec![])),
+            scratch: vec![],
+        }
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Inserts an entry, which is polled by the next call to `poll_ready`.
+    ///
+    /// Returns the slot identifying the entry.
+    pub fn insert(&mut self, val: T) -> usize {
+        if self.entries.available() == 0 {
+            let additional = cmp::max(self.entries.len(), INITIAL_CAPACITY);
+            self.entries.reserve_exact(additional);
+        }
+
+        let entry = self.entries.vacant_entry().expect("in-flight slab full");
+        let slot = entry.index();
+
+        entry.insert(Entry {
+            val: val,
+            notify: Arc::new(Notify {
+                slot: slot,
+                queued: AtomicBool::new(true),
+                ready: self.ready.clone(),
+            }),
+        });
+
+        self.ready.lock().unwrap().push(slot);
+
+        slot
+    }
+
+    /// Returns a reference to the entry in the given slot
+    pub fn get(&self, slot: usize) -> Option<&T> {
+        self.entries.get(slot).map(|entry| &entry.val)
+    }
+
+    /// Removes and returns the entry in the given slot
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot is vacant.
+    pub fn remove(&mut self, slot: usize) -> T {
+        self.entries.remove(slot).expect("invalid in-flight slot").val
+    }
+
+    /// Calls `f` with the slot of each entry notified since the last call, or
+    /// inserted since then.
+    ///
+    /// Must be called from a task. Notifications raised by futures polled
+    /// from `f` mark the entry ready again.
+    pub fn poll_ready<F>(&mut self, mut f: F)
+        where F: FnMut(usize, &mut T),
+    {
+        {
+            let mut slots = self.ready.lock().unwrap();
+            mem::swap(&mut self.scratch, &mut *slots);
+        }
+
+        for slot in self.scratch.drain(..) {
+            // Notifications may outlive their entry, in which case the slot
+            // is either vacant or reused. Polling a reused entry is spurious,
+            // but harmless.
+            if let Some(entry) = self.entries.get_mut(slot) {
+                // Cleared before polling, so that notifications raised while
+                // polling queue the entry again
+                entry.notify.queued.store(false, Ordering::SeqCst);
+
+                let event = UnparkEvent::new(entry.notify.clone(), 0);
+                let val = &mut entry.val;
+                task::with_unpark_event(event, || f(slot, val));
+            }
+        }
+    }
+}
+
+impl EventSet for Notify {
+    fn insert(&self, _: usize) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            self.ready.lock().unwrap().push(self.slot);
+        }
+    }
+}
@@ -1,6 +1,15 @@
 use bytes::{Buf, MutBuf, ReadExt, WriteExt};
 use futures::{Poll, Async};
-use std::io;
+use tokio_core::io::{Io, ReadHalf, WriteHalf};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::PollEvented;
+use std::{cmp, io, net};
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+/// The max number of buffers handed to a single vectored read or write
+const MAX_IOVECS: usize = 64;
 
 /// A refinement of `std::io::Read` for reading from non-blocking sources.
 ///
@@ -22,6 +31,22 @@ pub trait TryRead: io::Read {
     /// Pull some bytes from this source into the specified `Buf`, returning
     /// how many bytes were read.
     fn try_read_buf<B: MutBuf>(&mut self, buf: &mut B) -> Poll<usize, io::Error>;
+
+    /// Pull some bytes from this source into a list of buffers with a single
+    /// operation, returning how many bytes were read.
+    ///
+    /// The buffers are filled in order, as if they were one contiguous
+    /// buffer. See `ReadVec` for more details.
+    fn try_read_bufs(&mut self, bufs: &mut [&mut [u8]]) -> Poll<usize, io::Error>
+        where Self: ReadVec;
+
+    /// Copy some bytes from this source into the specified buffer without
+    /// consuming them, returning how many bytes were copied.
+    ///
+    /// If the source does not have any bytes available yet,
+    /// `Ok(Async::NotReady)` is returned. See `Peek` for more details.
+    fn try_peek(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error>
+        where Self: Peek;
 }
 
 impl<T: io::Read> TryRead for T {
@@ -40,6 +65,26 @@ impl<T: io::Read> TryRead for T {
             Err(e) => Err(e),
         }
     }
+
+    fn try_read_bufs(&mut self, bufs: &mut [&mut [u8]]) -> Poll<usize, io::Error>
+        where Self: ReadVec,
+    {
+        match self.read_vec(bufs) {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn try_peek(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error>
+        where Self: Peek,
+    {
+        match self.peek(buf) {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 /// A refinement of `std::io::Write` for reading from non-blocking sources.
@@ -61,6 +106,14 @@ pub trait TryWrite: io::Write {
     /// Write a `Buf` into this object, returning how many bytes were written.
     fn try_write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error>;
 
+    /// Write a list of buffers into this object with a single operation,
+    /// returning how many bytes were written.
+    ///
+    /// The buffers are written in order, as if they were one contiguous
+    /// buffer. See `WriteVec` for more details.
+    fn try_write_bufs(&mut self, bufs: &[&[u8]]) -> Poll<usize, io::Error>
+        where Self: WriteVec;
+
     /// Try flushing the underlying IO
     fn try_flush(&mut self) -> Poll<(), io::Error>;
 }
@@ -82,6 +135,16 @@ impl<T: io::Write> TryWrite for T {
         }
     }
 
+    fn try_write_bufs(&mut self, bufs: &[&[u8]]) -> Poll<usize, io::Error>
+        where Self: WriteVec,
+    {
+        match self.write_vec(bufs) {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
     fn try_flush(&mut self) -> Poll<(), io::Error> {
         match self.flush() {
             Ok(()) => Ok(Async::Ready(())),
@@ -90,3 +153,477 @@ impl<T: io::Write> TryWrite for T {
         }
     }
 }
+
+/// A refinement of `std::io::Read` for reading into a list of buffers at once.
+///
+/// Sockets implement this using `readv`, filling all buffers with a single
+/// system call. On unix, `tokio_core::net::TcpStream` and any `PollEvented`
+/// source exposing its file descriptor do as well.
+pub trait ReadVec: io::Read {
+
+    /// Pull some bytes from this source into a list of buffers, returning how
+    /// many bytes were read.
+    ///
+    /// The buffers are filled in order, as if they were one contiguous
+    /// buffer. Like `read`, it is not an error if fewer bytes than requested
+    /// are read.
+    ///
+    /// The default implementation only reads into the first non-empty buffer.
+    fn read_vec(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+            Some(buf) => self.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+/// A refinement of `std::io::Read` for looking at bytes without consuming
+/// them.
+///
+/// This allows, for example, sniffing which protocol a connection speaks
+/// before handing it off. Sockets implement this using `recv(MSG_PEEK)`,
+/// including `tokio_core::net::TcpStream` and, on unix, any `PollEvented`
+/// source exposing its file descriptor.
+pub trait Peek: io::Read {
+
+    /// Copy some bytes from this source into the specified buffer without
+    /// consuming them, returning how many bytes were copied.
+    ///
+    /// The bytes are returned again by the next `read` or `peek`. As with
+    /// `read`, returning 0 indicates that the source has reached EOF.
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// A refinement of `std::io::Write` for writing a list of buffers at once.
+///
+/// Sockets implement this using `writev`, handing all buffers to the kernel
+/// in a single system call. On unix, any `PollEvented` source exposing its
+/// file descriptor does as well, as does `tokio_core::net::TcpStream`, so that
+/// a socket registered with the reactor can be used with
+/// `CodecFramed::set_vectored_writes`.
+pub trait WriteVec: io::Write {
+
+    /// Write a list of buffers into this object, returning how many bytes were
+    /// written.
+    ///
+    /// The buffers are written in order, as if they were one contiguous
+    /// buffer. Like `write`, it is not an error if fewer bytes than available
+    /// are written.
+    ///
+    /// The default implementation only writes the first non-empty buffer.
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(buf) => self.write(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl WriteVec for Vec<u8> {
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let mut n = 0;
+
+        for buf in bufs {
+            self.extend_from_slice(buf);
+            n += buf.len();
+        }
+
+        Ok(n)
+    }
+}
+
+impl<'a> ReadVec for &'a [u8] {
+    fn read_vec(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let mut n = 0;
+
+        for buf in bufs {
+            n += try!(self.read(buf));
+        }
+
+        Ok(n)
+    }
+}
+
+impl<'a> Peek for &'a [u8] {
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = cmp::min(self.len(), buf.len());
+        buf[..n].copy_from_slice(&self[..n]);
+        Ok(n)
+    }
+}
+
+impl ReadVec for net::TcpStream {
+    #[cfg(unix)]
+    fn read_vec(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        sys::readv(self.as_raw_fd(), bufs)
+    }
+}
+
+#[cfg(unix)]
+impl Peek for net::TcpStream {
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        sys::peek(self.as_raw_fd(), buf)
+    }
+}
+
+impl WriteVec for net::TcpStream {
+    #[cfg(unix)]
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        sys::writev(self.as_raw_fd(), bufs)
+    }
+}
+
+/// Writes with `writev` once the reactor reports the source as writable. If
+/// the write would block, the current task is notified once it may succeed.
+#[cfg(unix)]
+impl<E> WriteVec for PollEvented<E>
+    where E: io::Write + AsRawFd,
+{
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        if !self.poll_write().is_ready() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not writable"));
+        }
+
+        let ret = sys::writev(self.get_ref().as_raw_fd(), bufs);
+
+        if let Err(ref e) = ret {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                self.need_write();
+            }
+        }
+
+        ret
+    }
+}
+
+/// Reads with `readv` once the reactor reports the source as readable. If the
+/// read would block, the current task is notified once it may succeed.
+#[cfg(unix)]
+impl<E> ReadVec for PollEvented<E>
+    where E: io::Read + AsRawFd,
+{
+    fn read_vec(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        if !self.poll_read().is_ready() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not readable"));
+        }
+
+        let ret = sys::readv(self.get_ref().as_raw_fd(), bufs);
+
+        if let Err(ref e) = ret {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                self.need_read();
+            }
+        }
+
+        ret
+    }
+}
+
+/// Peeks with `recv(MSG_PEEK)` once the reactor reports the source as
+/// readable. If the peek would block, the current task is notified once data
+/// arrives.
+#[cfg(unix)]
+impl<E> Peek for PollEvented<E>
+    where E: io::Read + AsRawFd,
+{
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.poll_read().is_ready() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not readable"));
+        }
+
+        let ret = sys::peek(self.get_ref().as_raw_fd(), buf);
+
+        if let Err(ref e) = ret {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                self.need_read();
+            }
+        }
+
+        ret
+    }
+}
+
+/// Writes with `writev` once the reactor reports the socket as writable.
+///
+/// `TcpStream` does not expose `need_write`, so when `writev` would block the
+/// first buffer is handed to `write` instead, which either writes it or
+/// registers the current task to be notified once the socket is writable.
+#[cfg(unix)]
+impl WriteVec for TcpStream {
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        if !TcpStream::poll_write(self).is_ready() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not writable"));
+        }
+
+        match sys::writev(self.as_raw_fd(), bufs) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                match bufs.iter().find(|buf| !buf.is_empty()) {
+                    Some(buf) => io::Write::write(self, buf),
+                    None => Ok(0),
+                }
+            }
+            ret => ret,
+        }
+    }
+}
+
+/// Reads with `readv` once the reactor reports the socket as readable.
+///
+/// As with `write_vec`, when `readv` would block the read falls back to
+/// `read` into the first buffer, which registers the current task to be
+/// notified once data arrives.
+#[cfg(unix)]
+impl ReadVec for TcpStream {
+    fn read_vec(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        if !TcpStream::poll_read(self).is_ready() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not readable"));
+        }
+
+        match sys::readv(self.as_raw_fd(), bufs) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+                    Some(buf) => io::Read::read(self, buf),
+                    None => Ok(0),
+                }
+            }
+            ret => ret,
+        }
+    }
+}
+
+/// Peeks using `TcpStream::peek`, which only peeks once the reactor reports
+/// the socket as readable, and notifies the current task once data arrives if
+/// the peek would block.
+impl Peek for TcpStream {
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+}
+
+// The halves of a split `Io` share the socket, so they cannot use `readv` or
+// `writev`
+impl<T: Io> ReadVec for ReadHalf<T> {}
+impl<T: Io> WriteVec for WriteHalf<T> {}
+
+/*
+ *
+ * ===== Peekable =====
+ *
+ */
+
+/// Adds `Peek` support to any `Io` by buffering peeked bytes.
+///
+/// Peeked bytes are read from the upstream `Io` and held on to until they are
+/// consumed by `read`. Unlike peeking at a socket, this works with the
+/// reactor, as a peek that would block is a read that would block.
+pub struct Peekable<T> {
+    upstream: T,
+    // Bytes read from upstream but not yet consumed
+    buf: Vec<u8>,
+    // Position of the first unconsumed byte in `buf`
+    pos: usize,
+}
+
+impl<T> Peekable<T> {
+    /// Create a new `Peekable` wrapping `upstream`
+    pub fn new(upstream: T) -> Peekable<T> {
+        Peekable::with_prefix(upstream, vec![])
+    }
+
+    /// Create a new `Peekable` that returns `prefix` before reading from
+    /// `upstream`.
+    ///
+    /// This is useful to hand off a connection after some bytes have already
+    /// been read from it.
+    pub fn with_prefix(upstream: T, prefix: Vec<u8>) -> Peekable<T> {
+        Peekable {
+            upstream: upstream,
+            buf: prefix,
+            pos: 0,
+        }
+    }
+
+    /// Returns the bytes that have been peeked at but not yet consumed.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Returns a reference to the upstream `Io`.
+    pub fn get_ref(&self) -> &T {
+        &self.upstream
+    }
+
+    /// Returns a mutable reference to the upstream `Io`.
+    ///
+    /// Reading from the `Io` directly skips over any buffered bytes.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.upstream
+    }
+
+    /// Consumes the `Peekable`, returning the upstream `Io` and the bytes
+    /// that have been peeked at but not yet consumed.
+    pub fn into_parts(mut self) -> (T, Vec<u8>) {
+        self.buf.drain(..self.pos);
+        (self.upstream, self.buf)
+    }
+}
+
+impl<T: io::Read> io::Read for Peekable<T> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            return self.upstream.read(dst);
+        }
+
+        let n = cmp::min(dst.len(), self.buf.len() - self.pos);
+        dst[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+
+        if self.pos == self.buf.len() {
+            // Everything has been consumed, release the buffer
+            self.buf = vec![];
+            self.pos = 0;
+        }
+
+        Ok(n)
+    }
+}
+
+impl<T: io::Read> ReadVec for Peekable<T> {}
+
+impl<T: io::Read> Peek for Peekable<T> {
+    fn peek(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        // Read from upstream until `dst` can be filled
+        while self.buf.len() - self.pos < dst.len() {
+            let len = self.buf.len();
+            let want = dst.len() - (len - self.pos);
+
+            self.buf.resize(len + want, 0);
+
+            match self.upstream.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+
+                    if n == 0 {
+                        // EOF
+                        break;
+                    }
+                }
+                Err(e) => {
+                    self.buf.truncate(len);
+
+                    // Return what is buffered so far, if anything
+                    if e.kind() == io::ErrorKind::WouldBlock && len > self.pos {
+                        break;
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        let n = cmp::min(dst.len(), self.buf.len() - self.pos);
+        dst[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        Ok(n)
+    }
+}
+
+impl<T: io::Write> io::Write for Peekable<T> {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.upstream.write(src)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.upstream.flush()
+    }
+}
+
+impl<T: WriteVec> WriteVec for Peekable<T> {
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.upstream.write_vec(bufs)
+    }
+}
+
+impl<T: Io> Io for Peekable<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        if self.pos < self.buf.len() {
+            Async::Ready(())
+        } else {
+            self.upstream.poll_read()
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.upstream.poll_write()
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use libc;
+    use smallvec::SmallVec;
+    use std::cmp;
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    pub fn readv(fd: RawFd, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let mut iovecs: SmallVec<[libc::iovec; 16]> = SmallVec::new();
+
+        let len = cmp::min(bufs.len(), super::MAX_IOVECS);
+
+        for buf in &mut bufs[..len] {
+            iovecs.push(libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len() as libc::size_t,
+            });
+        }
+
+        let ret = unsafe {
+            libc::readv(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    pub fn peek(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::recv(fd,
+                       buf.as_mut_ptr() as *mut libc::c_void,
+                       buf.len() as libc::size_t,
+                       libc::MSG_PEEK)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    pub fn writev(fd: RawFd, bufs: &[&[u8]]) -> io::Result<usize> {
+        let mut iovecs: SmallVec<[libc::iovec; 16]> = SmallVec::new();
+
+        for buf in &bufs[..cmp::min(bufs.len(), super::MAX_IOVECS)] {
+            iovecs.push(libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len() as libc::size_t,
+            });
+        }
+
+        let ret = unsafe {
+            libc::writev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
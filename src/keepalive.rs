@@ -0,0 +1,108 @@
+use futures::Future;
+use tokio_core::reactor::{Handle, Timeout};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Pings the peer of an idle connection, failing the connection if the peer
+/// does not answer in time.
+///
+/// Any frame read from the peer counts as an answer, so pings are only sent
+/// once no frame was read for `interval`.
+pub struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    handle: Handle,
+    // Created on first poll
+    timer: Option<Timeout>,
+    // The last time the connection was active
+    last_activity: Instant,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Waiting for the connection to be idle for `interval`
+    Idle,
+    // A ping is waiting for the transport to be writable
+    Ping,
+    // A ping was written, waiting for the peer to answer within `timeout`
+    AwaitingPong,
+}
+
+impl Keepalive {
+    /// Create a new `Keepalive`, the connection being considered active now.
+    pub fn new(interval: Duration, timeout: Duration, handle: &Handle) -> Keepalive {
+        Keepalive {
+            interval: interval,
+            timeout: timeout,
+            handle: handle.clone(),
+            timer: None,
+            last_activity: Instant::now(),
+            state: State::Idle,
+        }
+    }
+
+    /// The connection is active, either because a frame was read or because
+    /// reading is held back by the dispatcher.
+    pub fn activity(&mut self) {
+        self.last_activity = Instant::now();
+
+        // The peer is alive, there is no need to ping it. The timer is reset
+        // lazily once it fires.
+        self.state = State::Idle;
+    }
+
+    /// Returns true if a ping frame should be written. Returns an error if the
+    /// peer did not answer the last ping in time.
+    ///
+    /// Must be called from a task, on every tick.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        loop {
+            let fired = match self.timer {
+                Some(ref mut timer) => try!(timer.poll()).is_ready(),
+                None => true,
+            };
+
+            match self.state {
+                State::Idle => {
+                    if !fired {
+                        return Ok(false);
+                    }
+
+                    let deadline = self.last_activity + self.interval;
+                    let now = Instant::now();
+
+                    if now < deadline {
+                        // Activity since the timer was set, wait for the rest
+                        // of the interval
+                        self.timer = Some(try!(Timeout::new(deadline - now, &self.handle)));
+                        continue;
+                    }
+
+                    self.timer = None;
+                    self.state = State::Ping;
+                    return Ok(true);
+                }
+                State::Ping => return Ok(true),
+                State::AwaitingPong => {
+                    if !fired {
+                        return Ok(false);
+                    }
+
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "keepalive ping timed out"));
+                }
+            }
+        }
+    }
+
+    /// The ping frame requested by `poll` was written
+    pub fn ping_sent(&mut self) -> io::Result<()> {
+        debug_assert_eq!(self.state, State::Ping);
+
+        self.state = State::AwaitingPong;
+        self.timer = Some(try!(Timeout::new(self.timeout, &self.handle)));
+
+        // Register interest in the timer
+        self.poll().map(|_| ())
+    }
+}
@@ -11,15 +11,31 @@ extern crate smallvec;
 extern crate tokio_core;
 extern crate tokio_service;
 
+#[cfg(unix)]
+extern crate libc;
+
 #[macro_use]
 extern crate log;
 
+pub mod metrics;
+pub mod middleware;
 pub mod multiplex;
 pub mod pipeline;
 pub mod server;
 
+#[cfg(feature = "testing")]
+pub mod testing;
+
+mod context;
+mod drain;
+mod duplex;
 mod framing;
+mod in_flight;
 mod io;
+mod keepalive;
+mod registry;
 
-pub use framing::{Framed, Parse, Serialize};
-pub use io::{TryRead, TryWrite};
+pub use context::{request_context, RequestContext};
+pub use duplex::{duplex, duplex_with_capacity, Duplex};
+pub use framing::{Codec, CodecFramed, FlushPolicy, Framed, FramedRead, FramedWrite, Parse, ParseBuf, Serialize};
+pub use io::{Peek, Peekable, ReadVec, TryRead, TryWrite, WriteVec};
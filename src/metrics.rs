@@ -0,0 +1,52 @@
+//! Hooks for collecting metrics from pipeline and multiplex connections
+//!
+//! An `Observer` is attached to a connection using `pipeline::Server::set_observer`,
+//! `multiplex::Server::set_observer` or `pipeline::ClientBuilder::observer`.
+//! It is then called as the connection is processed, so that request rates,
+//! latencies and queue depths can be exported to a metrics system.
+//!
+//! The same observer is usually shared by all connections, so it is passed
+//! around as an `Arc`. Calls are made from the task processing the
+//! connection; implementations should be cheap and must not block.
+
+use std::time::Duration;
+
+/// Observes events on a pipeline or multiplex connection
+///
+/// All functions default to doing nothing, so implementations only need to
+/// override the events they are interested in. `()` can be used as an
+/// observer that ignores all events.
+pub trait Observer: Send + Sync {
+    /// Called when a frame is read from the transport.
+    fn frame_read(&self) {}
+
+    /// Called when a frame is written to the transport.
+    fn frame_written(&self) {}
+
+    /// Called when a request is dispatched, with the number of requests in
+    /// flight on the connection, including this one.
+    ///
+    /// On a server, the request is dispatched to the service. On a client,
+    /// the request is written to the transport.
+    fn request_dispatched(&self, in_flight: usize) {
+        let _ = in_flight;
+    }
+
+    /// Called when the response to a request is complete, with the time
+    /// elapsed since the request was dispatched.
+    fn response_completed(&self, latency: Duration) {
+        let _ = latency;
+    }
+
+    /// Called when a body chunk is written to the transport.
+    fn body_chunk_sent(&self) {}
+
+    /// Called when the transport could not be fully flushed because the
+    /// connection is not writable.
+    fn flush_blocked(&self) {}
+
+    /// Called when the connection is closed, for whatever reason.
+    fn connection_closed(&self) {}
+}
+
+impl Observer for () {}
@@ -0,0 +1,99 @@
+use futures::{Async, Future, Poll};
+use tokio_service::Service;
+use std::any::Any;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Turns panics of a service into errors
+///
+/// A panic while calling the wrapped service, or while polling its response
+/// future, fails the request with an `io::ErrorKind::Other` error instead of
+/// tearing down the task processing the connection along with every other
+/// request in flight on it.
+///
+/// The wrapped service may be left in an inconsistent state by the panic; it
+/// is the caller's responsibility to only wrap services for which that is
+/// acceptable.
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+/// Response future of `CatchPanic`
+pub struct CatchPanicFuture<F: Future> {
+    inner: Result<F, Option<F::Error>>,
+}
+
+impl<S> CatchPanic<S> {
+    /// Create a new `CatchPanic` wrapping `inner`
+    pub fn new(inner: S) -> CatchPanic<S> {
+        CatchPanic { inner: inner }
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Service for CatchPanic<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = CatchPanicFuture<S::Future>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let inner = &self.inner;
+
+        let res = panic::catch_unwind(AssertUnwindSafe(move || inner.call(req)));
+
+        CatchPanicFuture {
+            inner: res.map_err(|payload| Some(panic_error(payload))),
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<F> Future for CatchPanicFuture<F>
+    where F: Future,
+          F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = match self.inner {
+            Ok(ref mut f) => panic::catch_unwind(AssertUnwindSafe(|| f.poll())),
+            Err(ref mut e) => return Err(e.take().expect("future polled after completion")),
+        };
+
+        match res {
+            Ok(res) => res,
+            Err(payload) => {
+                // The future must not be polled again after panicking
+                self.inner = Err(None);
+                Err(panic_error(payload))
+            }
+        }
+    }
+}
+
+fn panic_error<E: From<io::Error>>(payload: Box<Any + Send>) -> E {
+    let msg = match payload.downcast_ref::<&'static str>() {
+        Some(msg) => format!("service panicked: {}", msg),
+        None => {
+            match payload.downcast_ref::<String>() {
+                Some(msg) => format!("service panicked: {}", msg),
+                None => "service panicked".to_string(),
+            }
+        }
+    };
+
+    debug!("{}", msg);
+    io::Error::new(io::ErrorKind::Other, msg).into()
+}
@@ -0,0 +1,186 @@
+use futures::{task, Async, Future, Poll};
+use futures::task::Task;
+use tokio_service::Service;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// Limits the number of requests processed by a service at once
+///
+/// Requests made while the limit is reached wait until a request in progress
+/// completes, or its response future is dropped, before being passed to the
+/// wrapped service.
+///
+/// Clones of a `ConcurrencyLimit` share the limit.
+pub struct ConcurrencyLimit<S> {
+    inner: Arc<S>,
+    limit: Arc<Mutex<Limit>>,
+}
+
+/// Response future of `ConcurrencyLimit`
+pub struct ConcurrencyLimitFuture<S: Service> {
+    service: Arc<S>,
+    limit: Arc<Mutex<Limit>>,
+    state: State<S::Request, S::Future>,
+}
+
+struct Limit {
+    max: usize,
+    // Number of requests passed to the service and not yet completed
+    in_flight: usize,
+    // Tasks waiting for a request to complete
+    waiters: Vec<Task>,
+}
+
+enum State<R, F> {
+    Waiting(R),
+    Active(F),
+    Done,
+}
+
+impl<S> ConcurrencyLimit<S> {
+    /// Create a new `ConcurrencyLimit` passing at most `max` requests at once
+    /// to `inner`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn new(inner: S, max: usize) -> ConcurrencyLimit<S> {
+        assert!(max > 0, "concurrency limit must be greater than 0");
+
+        ConcurrencyLimit {
+            inner: Arc::new(inner),
+            limit: Arc::new(Mutex::new(Limit {
+                max: max,
+                in_flight: 0,
+                waiters: vec![],
+            })),
+        }
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the number of requests currently passed to the wrapped service
+    pub fn in_flight(&self) -> usize {
+        self.limit.lock().unwrap().in_flight
+    }
+}
+
+impl<S> Service for ConcurrencyLimit<S>
+    where S: Service,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ConcurrencyLimitFuture<S>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        ConcurrencyLimitFuture {
+            service: self.inner.clone(),
+            limit: self.limit.clone(),
+            state: State::Waiting(req),
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        let limit = self.limit.lock().unwrap();
+
+        if limit.in_flight < limit.max {
+            self.inner.poll_ready()
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
+impl<S> Clone for ConcurrencyLimit<S> {
+    fn clone(&self) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit {
+            inner: self.inner.clone(),
+            limit: self.limit.clone(),
+        }
+    }
+}
+
+impl<S> ConcurrencyLimitFuture<S>
+    where S: Service,
+{
+    // Marks the request as completed, letting waiting requests proceed
+    fn release(&mut self) {
+        let waiters = {
+            let mut limit = match self.limit.lock() {
+                Ok(limit) => limit,
+                // Don't panic again while unwinding
+                Err(_) => return,
+            };
+
+            limit.in_flight -= 1;
+            mem::replace(&mut limit.waiters, vec![])
+        };
+
+        // Only one of the waiting requests can proceed, but waking all of
+        // them ensures that a request whose future was dropped while waiting
+        // does not hold up the others.
+        for waiter in waiters {
+            waiter.unpark();
+        }
+    }
+}
+
+impl<S> Future for ConcurrencyLimitFuture<S>
+    where S: Service,
+{
+    type Item = S::Response;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<S::Response, S::Error> {
+        if let State::Waiting(..) = self.state {
+            {
+                let mut limit = self.limit.lock().unwrap();
+
+                if limit.in_flight == limit.max {
+                    trace!("concurrency limit reached; max={}", limit.max);
+                    limit.waiters.push(task::park());
+                    return Ok(Async::NotReady);
+                }
+
+                limit.in_flight += 1;
+            }
+
+            let req = match mem::replace(&mut self.state, State::Done) {
+                State::Waiting(req) => req,
+                _ => unreachable!(),
+            };
+
+            self.state = State::Active(self.service.call(req));
+        }
+
+        let res = match self.state {
+            State::Active(ref mut f) => {
+                match f.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    res => res,
+                }
+            }
+            _ => panic!("future polled after completion"),
+        };
+
+        self.state = State::Done;
+        self.release();
+
+        res
+    }
+}
+
+impl<S> Drop for ConcurrencyLimitFuture<S>
+    where S: Service,
+{
+    fn drop(&mut self) {
+        // The response was canceled while in progress
+        if let State::Active(..) = self.state {
+            self.release();
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use context::{request_context, RequestContext};
+use futures::{Async, Future, Poll};
+use tokio_service::Service;
+use std::time::Instant;
+
+/// Logs the outcome and duration of each request
+///
+/// Events are logged at the `debug` level, tagged with the name of the
+/// service and, when available, the connection id and request sequence number
+/// of the current `RequestContext`.
+pub struct Log<S> {
+    name: &'static str,
+    inner: S,
+}
+
+/// Response future of `Log`
+pub struct LogFuture<F> {
+    name: &'static str,
+    inner: F,
+    cx: Option<RequestContext>,
+    started: Instant,
+}
+
+impl<S> Log<S> {
+    /// Create a new `Log` wrapping `inner`, identified by `name` in log events.
+    pub fn new(name: &'static str, inner: S) -> Log<S> {
+        Log {
+            name: name,
+            inner: inner,
+        }
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Service for Log<S>
+    where S: Service,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = LogFuture<S::Future>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let cx = request_context();
+
+        match cx {
+            Some(ref cx) => {
+                debug!("request started; service={}; conn={}; seq={}",
+                       self.name, cx.connection_id(), cx.request_seq());
+            }
+            None => debug!("request started; service={}", self.name),
+        }
+
+        LogFuture {
+            name: self.name,
+            inner: self.inner.call(req),
+            cx: cx,
+            started: Instant::now(),
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<F> Future for LogFuture<F>
+    where F: Future,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = self.inner.poll();
+
+        let outcome = match res {
+            Ok(Async::NotReady) => return res,
+            Ok(Async::Ready(_)) => "completed",
+            Err(_) => "failed",
+        };
+
+        let elapsed = self.started.elapsed();
+
+        match self.cx {
+            Some(ref cx) => {
+                debug!("request {}; service={}; conn={}; seq={}; elapsed={:?}",
+                       outcome, self.name, cx.connection_id(), cx.request_seq(), elapsed);
+            }
+            None => debug!("request {}; service={}; elapsed={:?}", outcome, self.name, elapsed),
+        }
+
+        res
+    }
+}
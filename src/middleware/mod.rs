@@ -0,0 +1,31 @@
+//! Composable wrappers adding behavior to a `Service`
+//!
+//! Each middleware wraps a `Service` and is itself a `Service` with the same
+//! request, response and error types. Responses, including `Message` values
+//! and their body streams, are passed through untouched, so a wrapped service
+//! can be handed to `pipeline::Server::new` or `multiplex::Server::new` in
+//! place of the original.
+//!
+//! Middleware compose by nesting. The outermost middleware sees requests
+//! first:
+//!
+//! ```rust,ignore
+//! let service = Log::new("echo", Timeout::new(CatchPanic::new(echo), timeout, &handle));
+//!
+//! pipeline::Server::new(service, transport)
+//! ```
+//!
+//! Middleware that produce errors of their own, such as `Timeout`, require
+//! the service's error type to be convertible from `io::Error`.
+
+mod catch_panic;
+mod concurrency_limit;
+mod logging;
+mod retry;
+mod timeout;
+
+pub use self::catch_panic::{CatchPanic, CatchPanicFuture};
+pub use self::concurrency_limit::{ConcurrencyLimit, ConcurrencyLimitFuture};
+pub use self::logging::{Log, LogFuture};
+pub use self::retry::{Retry, RetryFuture};
+pub use self::timeout::{Timeout, TimeoutFuture};
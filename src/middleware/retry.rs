@@ -0,0 +1,131 @@
+use futures::{Async, Future, Poll};
+use pipeline::Message;
+use tokio_service::Service;
+use std::sync::Arc;
+
+/// Retries failed requests, for use with `pipeline::Client`
+///
+/// Requests are retried at most `max_retries` times, as long as the error is
+/// considered retryable, which by default every error is. Only requests
+/// without a body are retried: the body stream of a request is consumed by
+/// the first attempt.
+///
+/// Requests are retried immediately; retrying is only safe for requests that
+/// are idempotent.
+pub struct Retry<S, E> {
+    inner: Arc<S>,
+    max_retries: usize,
+    // When not set, all errors are retried
+    should_retry: Option<Arc<Fn(&E) -> bool + Send + Sync>>,
+}
+
+/// Response future of `Retry`
+pub struct RetryFuture<S, T>
+    where S: Service,
+{
+    service: Arc<S>,
+    should_retry: Option<Arc<Fn(&S::Error) -> bool + Send + Sync>>,
+    // The request to retry, if it can be
+    request: Option<T>,
+    retries_left: usize,
+    inner: S::Future,
+}
+
+impl<S, T, B, E> Retry<S, E>
+    where S: Service<Request = Message<T, B>, Error = E>,
+          T: Clone,
+{
+    /// Create a new `Retry` wrapping `inner`, retrying failed requests at
+    /// most `max_retries` times.
+    pub fn new(inner: S, max_retries: usize) -> Retry<S, E> {
+        Retry {
+            inner: Arc::new(inner),
+            max_retries: max_retries,
+            should_retry: None,
+        }
+    }
+
+    /// Set the function deciding whether a request failing with an error is
+    /// retried.
+    pub fn set_should_retry<F>(&mut self, should_retry: F)
+        where F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.should_retry = Some(Arc::new(should_retry));
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S, T, B, E> Service for Retry<S, E>
+    where S: Service<Request = Message<T, B>, Error = E>,
+          T: Clone,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = E;
+    type Future = RetryFuture<S, T>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let request = match req {
+            Message::WithoutBody(ref msg) => Some(msg.clone()),
+            Message::WithBody(..) => None,
+        };
+
+        RetryFuture {
+            service: self.inner.clone(),
+            should_retry: self.should_retry.clone(),
+            request: request,
+            retries_left: self.max_retries,
+            inner: self.inner.call(req),
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<S, E> Clone for Retry<S, E> {
+    fn clone(&self) -> Retry<S, E> {
+        Retry {
+            inner: self.inner.clone(),
+            max_retries: self.max_retries,
+            should_retry: self.should_retry.clone(),
+        }
+    }
+}
+
+impl<S, T, B> Future for RetryFuture<S, T>
+    where S: Service<Request = Message<T, B>>,
+          T: Clone,
+{
+    type Item = S::Response;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<S::Response, S::Error> {
+        loop {
+            let err = match self.inner.poll() {
+                Err(e) => e,
+                res => return res,
+            };
+
+            let retry = self.retries_left > 0 && match self.should_retry {
+                Some(ref should_retry) => should_retry(&err),
+                None => true,
+            };
+
+            let req = match self.request {
+                Some(ref req) if retry => req.clone(),
+                _ => return Err(err),
+            };
+
+            self.retries_left -= 1;
+
+            debug!("retrying request; retries_left={}", self.retries_left);
+            self.inner = self.service.call(Message::WithoutBody(req));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{self, Handle};
+use tokio_service::Service;
+use std::io;
+use std::time::Duration;
+
+/// Fails requests that are not responded to within a timeout
+///
+/// When the timeout elapses, the response future of the wrapped service is
+/// dropped and an `io::ErrorKind::TimedOut` error is returned instead.
+pub struct Timeout<S> {
+    inner: S,
+    timeout: Duration,
+    handle: Handle,
+}
+
+/// Response future of `Timeout`
+pub struct TimeoutFuture<F> {
+    inner: F,
+    // Failing to create the timeout is reported when the future is polled
+    timeout: io::Result<reactor::Timeout>,
+}
+
+impl<S> Timeout<S> {
+    /// Create a new `Timeout` failing requests to `inner` that take longer
+    /// than `timeout`.
+    pub fn new(inner: S, timeout: Duration, handle: &Handle) -> Timeout<S> {
+        Timeout {
+            inner: inner,
+            timeout: timeout,
+            handle: handle.clone(),
+        }
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Service for Timeout<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TimeoutFuture<S::Future>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        TimeoutFuture {
+            inner: self.inner.call(req),
+            timeout: reactor::Timeout::new(self.timeout, &self.handle),
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<F> Future for TimeoutFuture<F>
+    where F: Future,
+          F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        // Give the response a chance to complete first
+        if let Async::Ready(resp) = try!(self.inner.poll()) {
+            return Ok(Async::Ready(resp));
+        }
+
+        let timeout = match self.timeout {
+            Ok(ref mut timeout) => timeout,
+            Err(ref e) => return Err(io::Error::new(e.kind(), e.to_string()).into()),
+        };
+
+        match try!(timeout.poll()) {
+            Async::Ready(()) => {
+                debug!("request timed out");
+                Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out").into())
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use super::RequestId;
+use std::collections::HashMap;
+use std::io;
+
+/// Tracks the flow control windows of the body streams of a connection
+///
+/// Windows are frame credits: each body frame consumes one credit, whatever
+/// the size of its chunk. There is no frame to negotiate the initial windows,
+/// both peers must be configured with the same ones.
+pub struct FlowControl {
+    // Window each stream starts with
+    stream_window: usize,
+    // Body frames the peer may still send on the connection
+    recv_connection: usize,
+    // Body frames the peer may still send, by stream
+    recv_streams: HashMap<RequestId, usize>,
+    // Credit consumed by the body streams and not granted to the peer yet
+    pending_connection: usize,
+    pending_streams: Vec<(RequestId, usize)>,
+    // Body frames that may still be sent on the connection
+    send_connection: usize,
+    // Body frames that may still be sent, by stream
+    send_streams: HashMap<RequestId, usize>,
+}
+
+impl FlowControl {
+    /// Create a new `FlowControl` with the given initial windows
+    pub fn new(stream_window: usize, connection_window: usize) -> FlowControl {
+        FlowControl {
+            stream_window: stream_window,
+            recv_connection: connection_window,
+            recv_streams: HashMap::new(),
+            pending_connection: 0,
+            pending_streams: vec![],
+            send_connection: connection_window,
+            send_streams: HashMap::new(),
+        }
+    }
+
+    /// The peer started sending a body on stream `id`
+    pub fn open_recv(&mut self, id: RequestId) {
+        self.recv_streams.insert(id, self.stream_window);
+    }
+
+    /// The peer is done sending the body of stream `id`
+    pub fn close_recv(&mut self, id: RequestId) {
+        self.recv_streams.remove(&id);
+        self.pending_streams.retain(|&(pending, _)| pending != id);
+    }
+
+    /// A body frame was received on stream `id`. Returns an error if the peer
+    /// exceeded either window.
+    pub fn recv(&mut self, id: RequestId) -> io::Result<()> {
+        if self.recv_connection == 0 {
+            return Err(window_exceeded());
+        }
+
+        if let Some(window) = self.recv_streams.get_mut(&id) {
+            if *window == 0 {
+                return Err(window_exceeded());
+            }
+
+            *window -= 1;
+        }
+
+        self.recv_connection -= 1;
+        Ok(())
+    }
+
+    /// `n` body frames received on stream `id` were consumed, the peer may
+    /// send as many more.
+    pub fn release(&mut self, id: RequestId, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        self.recv_connection += n;
+        self.pending_connection += n;
+
+        // No credit is granted to streams that are done
+        if let Some(window) = self.recv_streams.get_mut(&id) {
+            *window += n;
+
+            match self.pending_streams.iter().position(|&(pending, _)| pending == id) {
+                Some(i) => self.pending_streams[i].1 += n,
+                None => self.pending_streams.push((id, n)),
+            }
+        }
+    }
+
+    /// Returns the next window update to send to the peer, `None` identifying
+    /// the connection window.
+    pub fn next_update(&mut self) -> Option<(Option<RequestId>, usize)> {
+        if !self.pending_streams.is_empty() {
+            let (id, credit) = self.pending_streams.remove(0);
+            return Some((Some(id), credit));
+        }
+
+        if self.pending_connection > 0 {
+            let credit = self.pending_connection;
+            self.pending_connection = 0;
+            return Some((None, credit));
+        }
+
+        None
+    }
+
+    /// Started sending a body on stream `id`
+    pub fn open_send(&mut self, id: RequestId) {
+        self.send_streams.insert(id, self.stream_window);
+    }
+
+    /// Done sending the body of stream `id`
+    pub fn close_send(&mut self, id: RequestId) {
+        self.send_streams.remove(&id);
+    }
+
+    /// Returns true if a body frame may be sent on stream `id`
+    pub fn can_send(&self, id: RequestId) -> bool {
+        self.send_connection > 0 &&
+            self.send_streams.get(&id).map(|&window| window > 0).unwrap_or(false)
+    }
+
+    /// A body frame was sent on stream `id`
+    pub fn send(&mut self, id: RequestId) {
+        debug_assert!(self.can_send(id));
+
+        self.send_connection -= 1;
+
+        if let Some(window) = self.send_streams.get_mut(&id) {
+            *window -= 1;
+        }
+    }
+
+    /// The peer granted `credit` more body frames on stream `id`, or on the
+    /// connection if `id` is `None`.
+    pub fn window_update(&mut self, id: Option<RequestId>, credit: usize) {
+        match id {
+            Some(id) => {
+                // Updates may race with the end of the stream
+                if let Some(window) = self.send_streams.get_mut(&id) {
+                    *window = window.saturating_add(credit);
+                }
+            }
+            None => {
+                self.send_connection = self.send_connection.saturating_add(credit);
+            }
+        }
+    }
+}
+
+fn window_exceeded() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "peer exceeded flow control window")
+}
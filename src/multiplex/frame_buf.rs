@@ -22,7 +22,8 @@ pub struct FrameDeque<T> {
 
 const MAX_BLOCKS: usize = 16;
 const INITIAL_BLOCK_SIZE: usize = 32;
-const MAX_CAPACITY: usize = 1_048_576;
+/// Capacities must be smaller than this
+pub const MAX_CAPACITY: usize = 1_048_576;
 
 struct Inner<T> {
     // Max number of elements that can be stored
@@ -42,6 +43,9 @@ struct Slot<T> {
 
 impl<T> FrameBuf<T> {
     /// Return a new `FrameBuf` with the given capacity
+    ///
+    /// Slots are allocated in blocks as frames are pushed, starting with 32,
+    /// so a large capacity does not allocate up front.
     pub fn with_capacity(capacity: usize) -> FrameBuf<T> {
         assert!(capacity < MAX_CAPACITY,
                 "requested frame buffer capacity too large; max={}; requested={}",
@@ -132,6 +136,14 @@ impl<T> FrameDeque<T> {
     }
 }
 
+impl<T> Drop for FrameDeque<T> {
+    fn drop(&mut self) {
+        // Return the slots to the buffer
+        while let Some(_) = self.pop() {
+        }
+    }
+}
+
 impl<T> Inner<T> {
     fn with_capacity(mut capacity: usize) -> Inner<T> {
         capacity = cmp::max(INITIAL_BLOCK_SIZE, capacity.next_power_of_two());
@@ -207,7 +219,7 @@ impl<T> Inner<T> {
 
 #[cfg(test)]
 mod test {
-    use super::{FrameBuf};
+    use super::{FrameBuf, MAX_CAPACITY};
 
     #[test]
     fn test_capacity() {
@@ -270,6 +282,17 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_allocating_lazily() {
+        let fb = FrameBuf::with_capacity(MAX_CAPACITY - 1);
+        let d = fb.deque();
+
+        assert_eq!(0, fb.allocated());
+
+        d.push(0);
+        assert_eq!(32, fb.allocated());
+    }
+
     #[test]
     #[should_panic]
     fn test_attempting_allocation_past_capacity() {
@@ -281,6 +304,19 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_dropping_deque_frees_slots() {
+        let fb = FrameBuf::with_capacity(32);
+
+        for _ in 0..2 {
+            let d = fb.deque();
+
+            for i in 0..32 {
+                d.push(i);
+            }
+        }
+    }
+
     #[test]
     fn test_multiple_deque() {
         let fb = FrameBuf::with_capacity(64);
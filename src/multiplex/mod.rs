@@ -25,15 +25,45 @@
 //! timeout is set. If no further frames are able to be read before the timeout
 //! expires, then the connection is killed.
 //!
-//! ## Current status
+//! ## Response scheduling
 //!
-//! As of now, the implementation only supports multiplexed requests &
-//! responses without streaming bodies.
+//! Completed responses and their body chunks are written one frame at a time,
+//! in the order decided by a `Schedule`. By default responses are written in
+//! the order they complete; `RoundRobin` and `Weighted` interleave the frames
+//! of several responses so that large response bodies do not delay small
+//! responses. See `Server::set_schedule`.
+//!
+//! ## Flow control
+//!
+//! Request body chunks are buffered until the body stream consumes them. By
+//! default, once 128 chunks are buffered across all body streams, no more
+//! frames are read from the connection until the body streams catch up, so a
+//! single slow body stream stalls every other request on the connection.
+//!
+//! Flow control avoids the stall, see `Server::set_flow_control_windows`.
+//! Each body stream, and the connection as a whole, then has a window: the
+//! number of body frames the peer may send before it is granted more credit.
+//! Credit is granted with `Frame::WindowUpdate` as the receiving body stream
+//! consumes chunks, so the `Transport` must be able to encode and decode these
+//! frames. Response bodies are likewise only written as the peer grants
+//! credit.
+//!
+//! Windows are frame credits, not bytes: each body frame consumes one credit,
+//! whatever the size of its chunk. There is no frame to negotiate the initial
+//! windows, both peers must be configured with the same ones.
+//!
+//! ## Keepalive
+//!
+//! As with the pipeline dispatcher, the connection can be kept alive with
+//! `Frame::Ping` and `Frame::Pong`, see `Server::set_keepalive`.
 
+mod flow_control;
 mod frame_buf;
 mod multiplex;
+mod schedule;
 mod server;
 
+pub use self::schedule::{Fifo, RoundRobin, Schedule, Weighted};
 pub use self::server::Server;
 
 use tokio_core::io::FramedIo;
@@ -60,6 +90,21 @@ pub enum Frame<T, B, E> {
     Body(RequestId, Option<B>),
     /// Error
     Error(RequestId, E),
+    /// Grants the peer credit to send more body frames on the given stream,
+    /// or on the connection when the `RequestId` is `None`. Only written and
+    /// honored when flow control is enabled, see the module docs for more
+    /// details.
+    WindowUpdate(Option<RequestId>, usize),
+    /// Keepalive ping. Written when keepalive is enabled and no frame was
+    /// read from the connection for a while. A `Ping` read from the transport
+    /// is answered with a `Pong`.
+    Ping,
+    /// Answer to a `Ping`
+    Pong,
+    /// Written when the connection starts draining, if enabled, telling the
+    /// peer to stop sending requests and migrate to another connection. See
+    /// `server::Drain`.
+    GoAway,
     /// Final frame sent in each transport direction
     Done,
 }
@@ -138,6 +183,10 @@ pub trait Transport {
     fn poll_write(&mut self) -> Async<()>;
 
     /// Write a message to the `Transport`
+    ///
+    /// An error returned when writing a `Message` frame only fails that
+    /// message, the dispatcher keeps using the transport. I/O errors should be
+    /// reported by `flush`.
     fn write(&mut self, req: Frame<Self::In, Self::BodyIn, Self::Error>) -> Poll<(), io::Error>;
 
     /// Flush pending writes to the socket
@@ -189,7 +238,8 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::MessageWithBody(id, _, _) => Some(id),
             Frame::Body(id, _) => Some(id),
             Frame::Error(id, _) => Some(id),
-            Frame::Done => None,
+            Frame::WindowUpdate(id, _) => id,
+            Frame::Ping | Frame::Pong | Frame::GoAway | Frame::Done => None,
         }
     }
 
@@ -200,6 +250,10 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::MessageWithBody(_, v, _) => v,
             Frame::Body(..) => panic!("called `Frame::unwrap_msg()` on a `Body` value"),
             Frame::Error(..) => panic!("called `Frame::unwrap_msg()` on an `Error` value"),
+            Frame::WindowUpdate(..) => panic!("called `Frame::unwrap_msg()` on a `WindowUpdate` value"),
+            Frame::Ping => panic!("called `Frame::unwrap_msg()` on a `Ping` value"),
+            Frame::Pong => panic!("called `Frame::unwrap_msg()` on a `Pong` value"),
+            Frame::GoAway => panic!("called `Frame::unwrap_msg()` on a `GoAway` value"),
             Frame::Done => panic!("called `Frame::unwrap_msg()` on a `Done` value"),
         }
     }
@@ -211,6 +265,10 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::Message(..) => panic!("called `Frame::unwrap_body()` on a `Message` value"),
             Frame::MessageWithBody(..) => panic!("called `Frame::unwrap_body()` on a `MessageWithBody` value"),
             Frame::Error(..) => panic!("called `Frame::unwrap_body()` on an `Error` value"),
+            Frame::WindowUpdate(..) => panic!("called `Frame::unwrap_body()` on a `WindowUpdate` value"),
+            Frame::Ping => panic!("called `Frame::unwrap_body()` on a `Ping` value"),
+            Frame::Pong => panic!("called `Frame::unwrap_body()` on a `Pong` value"),
+            Frame::GoAway => panic!("called `Frame::unwrap_body()` on a `GoAway` value"),
             Frame::Done => panic!("called `Frame::unwrap_body()` on a `Done` value"),
         }
     }
@@ -222,6 +280,10 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::Body(..) => panic!("called `Frame::unwrap_err()` on a `Body` value"),
             Frame::Message(..) => panic!("called `Frame::unwrap_err()` on a `Message` value"),
             Frame::MessageWithBody(..) => panic!("called `Frame::unwrap_err()` on a `MessageWithBody` value"),
+            Frame::WindowUpdate(..) => panic!("called `Frame::unwrap_err()` on a `WindowUpdate` value"),
+            Frame::Ping => panic!("called `Frame::unwrap_err()` on a `Ping` value"),
+            Frame::Pong => panic!("called `Frame::unwrap_err()` on a `Pong` value"),
+            Frame::GoAway => panic!("called `Frame::unwrap_err()` on a `GoAway` value"),
             Frame::Done => panic!("called `Frame::unwrap_message()` on a `Done` value"),
         }
     }
@@ -246,6 +308,10 @@ impl<T, B, E> fmt::Debug for Frame<T, B, E>
             Frame::MessageWithBody(ref id, ref v, _) => write!(fmt, "Frame::MessageWithBody({:?}, {:?}, Sender)", id, v),
             Frame::Body(ref id, ref v) => write!(fmt, "Frame::Body({:?}, {:?})", id, v),
             Frame::Error(ref id, ref v) => write!(fmt, "Frame::Error({:?}, {:?})", id, v),
+            Frame::WindowUpdate(ref id, ref v) => write!(fmt, "Frame::WindowUpdate({:?}, {:?})", id, v),
+            Frame::Ping => write!(fmt, "Frame::Ping"),
+            Frame::Pong => write!(fmt, "Frame::Pong"),
+            Frame::GoAway => write!(fmt, "Frame::GoAway"),
             Frame::Done => write!(fmt, "Frame::Done"),
         }
     }
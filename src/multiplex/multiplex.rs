@@ -1,8 +1,19 @@
 use super::{Frame, Message, Error, RequestId, Transport};
-use super::frame_buf::{FrameBuf, FrameDeque};
+use super::flow_control::FlowControl;
+use super::frame_buf::{self, FrameBuf, FrameDeque};
+use super::schedule::{Fifo, Schedule};
+use context;
+use drain::{self, Drain};
+use keepalive::Keepalive;
+use server::ConnectionState;
+use metrics::Observer;
 use futures::{Future, Poll, Async};
-use futures::stream::{Stream};
+use futures::stream::{Stream, Sender, FutureSender};
+use tokio_core::reactor::Handle;
+use std::collections::HashMap;
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
 /*
  * TODO:
@@ -28,20 +39,50 @@ pub struct Multiplex<S, T>
     run: bool,
     // The transport wrapping the connection.
     transport: T,
-    // The `Sender` for the in-flight request body streams
-    // out_bodies: HashMap<RequestId, BodySender<T::BodyOut, S::Error>>,
+    // The in-flight request body streams
+    out_bodies: HashMap<RequestId, OutBody<T::BodyOut, S::Error>>,
+    // Completed responses waiting to be written
+    responses: HashMap<RequestId, Result<Message<S::InMsg, S::InBodyStream>, S::Error>>,
     // The in-flight response body streams
-    // in_bodies: HashMap<RequestId, S::InBodyStream>,
+    in_bodies: HashMap<RequestId, S::InBodyStream>,
+    // Response body streams that had no chunk available when last polled
+    blocked_bodies: Vec<RequestId>,
+    // Response body streams waiting for the peer to grant credit
+    window_blocked: Vec<RequestId>,
+    // Flow control windows of the body streams, if enabled
+    flow: Option<FlowControl>,
+    // Decides the order in which response frames are written
+    schedule: Box<Schedule<T::Out> + Send>,
     // True when the transport is fully flushed
     is_flushed: bool,
     // Glues the service with the pipeline task
     dispatch: S,
     // Buffer of pending messages for the dispatch
     dispatch_deque: FrameDeque<Frame<T::Out, T::BodyOut, S::Error>>,
-    // Storage for buffered frames
-    // frame_buf: FrameBuf<Frame<T::Out, T::BodyOut, S::Error>>,
+    // Storage for request body chunks not consumed yet. Bounded by the
+    // connection window with flow control, and by stopping to read frames
+    // once full otherwise.
+    body_buf: FrameBuf<T::BodyOut>,
+    // Number of request body chunks read and not consumed yet
+    body_chunks: usize,
     // Temporary storage for RequestIds...
-    // scratch: Vec<RequestId>,
+    scratch: Vec<RequestId>,
+    // Notified of connection events
+    observer: Arc<Observer>,
+    // Identifies the connection in log events
+    conn: usize,
+    // Pings the peer when the connection is idle
+    keepalive: Option<Keepalive>,
+    // True when a ping was read and is yet to be answered
+    pong_pending: bool,
+    // Signals the connection to drain
+    drain: Drain,
+    // True once the connection started draining
+    draining: bool,
+    // True if `Frame::GoAway` is written when draining starts
+    goaway: bool,
+    // True when draining started and `Frame::GoAway` is yet to be written
+    goaway_pending: bool,
 }
 
 /// Dispatch messages from the transport to the service
@@ -68,16 +109,24 @@ pub trait Dispatch {
     /// The `Dispatch` is ready to accept another message
     fn is_ready(&self) -> bool;
 
-    /// RPC currently in flight
-    fn has_in_flight(&self) -> bool;
+    /// Number of RPCs currently in flight
+    fn in_flight(&self) -> usize;
+}
+
+// A request body stream being received
+struct OutBody<B, E> {
+    // `None` once the body is done
+    sender: Option<BodySender<B, E>>,
+    // Chunks received from the peer and not sent to the body stream yet
+    chunks: FrameDeque<B>,
+    // True once the peer sent the end of the body
+    eof: bool,
 }
 
-/*
 enum BodySender<B, E> {
     Ready(Sender<B, E>),
-    Busy(FutureSender<B, E>, FrameDeque<Option<B>>),
+    Busy(FutureSender<B, E>),
 }
-*/
 
 /*
  *
@@ -98,28 +147,155 @@ impl<S, T, E> Multiplex<S, T>
         Ok(Multiplex {
             run: true,
             transport: transport,
-            // out_bodies: HashMap::new(),
-            // in_bodies: HashMap::new(),
+            out_bodies: HashMap::new(),
+            responses: HashMap::new(),
+            in_bodies: HashMap::new(),
+            blocked_bodies: vec![],
+            window_blocked: vec![],
+            flow: None,
+            schedule: Box::new(Fifo::new()),
             is_flushed: true,
             dispatch: dispatch,
             dispatch_deque: frame_buf.deque(),
-            // frame_buf: frame_buf,
-            // scratch: vec![],
+            body_buf: FrameBuf::with_capacity(MAX_BUFFERED_FRAMES),
+            body_chunks: 0,
+            scratch: vec![],
+            observer: Arc::new(()),
+            conn: context::next_connection_id(),
+            keepalive: None,
+            pong_pending: false,
+            drain: drain::new(),
+            draining: false,
+            goaway: false,
+            goaway_pending: false,
         })
     }
 
+    /// Returns the id identifying this connection in log events
+    pub fn connection_id(&self) -> usize {
+        self.conn
+    }
+
+    /// Returns a snapshot of the state of the connection
+    pub fn state(&self) -> ConnectionState {
+        ConnectionState {
+            connection_id: self.conn,
+            in_flight: self.dispatch.in_flight() + self.responses.len(),
+            in_body: !self.in_bodies.is_empty(),
+            out_body: !self.out_bodies.is_empty(),
+            dispatch_deque: self.dispatch_deque.len(),
+            is_flushed: self.is_flushed,
+            draining: self.draining,
+        }
+    }
+
+    /// Sets the observer notified of connection events
+    pub fn set_observer(&mut self, observer: Arc<Observer>) {
+        self.observer = observer;
+    }
+
+    /// Sets the schedule deciding the order in which response frames are
+    /// written
+    pub fn set_schedule(&mut self, schedule: Box<Schedule<T::Out> + Send>) {
+        self.schedule = schedule;
+    }
+
+    /// Enables flow control, with the given initial windows of each body
+    /// stream and of the connection, counted in body frames
+    ///
+    /// Must be called before any frame is read. Returns an error if either
+    /// window is zero, or if the connection window is larger than the number
+    /// of body frames that can be buffered.
+    pub fn set_flow_control_windows(&mut self, stream: usize, connection: usize) -> io::Result<()> {
+        if stream == 0 || connection == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "flow control windows must be greater than zero"));
+        }
+
+        if connection >= frame_buf::MAX_CAPACITY {
+            debug!("flow control window too large; max={}; requested={}",
+                   frame_buf::MAX_CAPACITY - 1, connection);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "flow control window too large"));
+        }
+
+        assert!(self.out_bodies.is_empty() && self.in_bodies.is_empty(),
+                "flow control windows set after bodies started streaming");
+
+        self.flow = Some(FlowControl::new(stream, connection));
+
+        // The window bounds the buffered frames, slots are still allocated in
+        // blocks as chunks are buffered
+        self.body_buf = FrameBuf::with_capacity(connection);
+
+        Ok(())
+    }
+
+    /// Pings the peer once no frame was read for `interval`, closing the
+    /// connection if no frame is read within `timeout` of the ping
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration, handle: &Handle) {
+        self.keepalive = Some(Keepalive::new(interval, timeout, handle));
+    }
+
+    /// Sets whether `Frame::GoAway` is written when draining starts
+    pub fn set_goaway(&mut self, enabled: bool) {
+        self.goaway = enabled;
+    }
+
+    /// Returns a handle used to drain the connection
+    pub fn drain_handle(&self) -> Drain {
+        self.drain.clone()
+    }
+
+    /// Returns a mutable reference to the dispatch
+    pub fn dispatch_mut(&mut self) -> &mut S {
+        &mut self.dispatch
+    }
+
     /// Returns true if the multiplexer has nothing left to do
     fn is_done(&self) -> bool {
-        !self.run && self.is_flushed && !self.dispatch.has_in_flight()
+        !self.run && self.is_flushed && self.dispatch.in_flight() == 0 &&
+            self.responses.is_empty() && self.in_bodies.is_empty() &&
+            self.out_bodies.is_empty() && !self.goaway_pending
+    }
+
+    fn poll_drain(&mut self) {
+        if !self.draining && drain::poll(&self.drain) {
+            debug!("draining connection; conn={}; in_flight={}", self.conn, self.dispatch.in_flight());
+            self.draining = true;
+            self.goaway_pending = self.goaway;
+        }
     }
 
     fn read_out_frames(&mut self) -> io::Result<()> {
-        // try!(self.flush_out_bodies());
+        // Body streams may have consumed chunks since the last tick
+        self.flush_out_bodies();
 
         while self.run {
+            // Once draining, frames are only read for the request bodies in
+            // flight
+            if self.draining && self.out_bodies.is_empty() {
+                trace!("connection draining, done reading; conn={}", self.conn);
+                self.run = false;
+                break;
+            }
+
+            // Without flow control, the peer is held back by no longer
+            // reading frames once the body buffer is full
+            if self.flow.is_none() && self.body_chunks >= MAX_BUFFERED_FRAMES {
+                trace!("out body buffer full, waiting for body streams; conn={}", self.conn);
+                break;
+            }
+
             // TODO: Only read frames if there is available space in the frame
             // buffer
             if let Async::Ready(frame) = try!(self.transport.read()) {
+                self.observer.frame_read();
+
+                if let Some(ref mut keepalive) = self.keepalive {
+                    keepalive.activity();
+                }
+
                 try!(self.process_out_frame(frame));
             } else {
                 break;
@@ -133,6 +309,8 @@ impl<S, T, E> Multiplex<S, T>
         while self.dispatch.is_ready() {
             match self.dispatch_deque.pop() {
                 Some(Frame::Message(request_id, msg)) => {
+                    trace!("dispatching queued message; conn={}; id={:?}", self.conn, request_id);
+
                     if let Err(_) = self.dispatch.dispatch(request_id, msg) {
                         unimplemented!();
                     }
@@ -145,58 +323,121 @@ impl<S, T, E> Multiplex<S, T>
         Ok(())
     }
 
-    /*
-    fn flush_out_bodies(&mut self) -> io::Result<()> {
+    fn flush_out_bodies(&mut self) {
         self.scratch.clear();
+        self.scratch.extend(self.out_bodies.keys().cloned());
 
-        for (request_id, body_sender) in self.out_bodies.iter_mut() {
-            if body_sender.flush() {
-                self.scratch.push(*request_id);
-            }
+        for i in 0..self.scratch.len() {
+            let id = self.scratch[i];
+            self.flush_out_body(id);
         }
+    }
+
+    fn flush_out_body(&mut self, id: RequestId) {
+        let (consumed, done) = match self.out_bodies.get_mut(&id) {
+            Some(body) => body.flush(),
+            None => return,
+        };
 
-        // Purge the scratch
-        for request_id in &self.scratch {
-            self.out_bodies.remove(request_id);
+        self.body_chunks -= consumed;
+
+        // Grant the peer credit for the consumed chunks
+        if let Some(ref mut flow) = self.flow {
+            flow.release(id, consumed);
         }
 
-        Ok(())
+        if done {
+            trace!("out body done; conn={}; id={:?}", self.conn, id);
+            self.out_bodies.remove(&id);
+        }
     }
-    */
 
     fn process_out_frame(&mut self, frame: Frame<T::Out, T::BodyOut, E>) -> io::Result<()> {
-        trace!("Multiplex::process_out_frame");
         match frame {
+            Frame::Message(id, _) | Frame::MessageWithBody(id, _, _) if self.draining => {
+                // Sent before the peer saw the goaway frame
+                trace!("connection draining, rejecting message; conn={}; id={:?}", self.conn, id);
+                let err: Error<E> = Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection draining"));
+                self.responses.insert(id, Err(err.into()));
+                self.schedule.ready(id);
+            }
             Frame::Message(id, out_message) => {
-                trace!("   --> read out message; id={:?}", id);
+                trace!("read out message; conn={}; id={:?}", self.conn, id);
+                self.dispatch_out_message(id, out_message);
+            }
+            Frame::MessageWithBody(id, out_message, body_sender) => {
+                trace!("read out message with body; conn={}; id={:?}", self.conn, id);
 
-                if self.dispatch.is_ready() {
-                    trace!("   --> dispatch ready -- dispatching");
+                // Body chunks are buffered until the body stream consumes
+                // them, up to the stream's window.
+                if let Some(ref mut flow) = self.flow {
+                    flow.open_recv(id);
+                }
 
-                    // Only should be here if there are no queued messages
-                    assert!(self.dispatch_deque.is_empty());
+                self.out_bodies.insert(id, OutBody {
+                    sender: Some(BodySender::Ready(body_sender)),
+                    chunks: self.body_buf.deque(),
+                    eof: false,
+                });
 
-                    if let Err(_) = self.dispatch.dispatch(id, out_message) {
-                        // TODO: Should dispatch be infalliable
-                        unimplemented!();
+                self.dispatch_out_message(id, out_message);
+            }
+            Frame::Body(id, Some(chunk)) => {
+                trace!("read out body chunk; conn={}; id={:?}", self.conn, id);
+                if let Some(ref mut flow) = self.flow {
+                    try!(flow.recv(id));
+                }
+
+                match self.out_bodies.get_mut(&id) {
+                    Some(body) => {
+                        body.chunks.push(chunk);
+                        self.body_chunks += 1;
+                    }
+                    None => {
+                        trace!("dropping out body chunk, interest canceled; conn={}; id={:?}", self.conn, id);
+
+                        if let Some(ref mut flow) = self.flow {
+                            flow.release(id, 1);
+                        }
                     }
-                } else {
-                    trace!("   --> dispatch not ready");
-                    // Queue the dispatch buffer
-                    self.dispatch_deque.push(Frame::Message(id, out_message));
                 }
+
+                self.flush_out_body(id);
             }
-            Frame::MessageWithBody(_id, _out_message, _body_sender) => {
-                unimplemented!();
+            Frame::Body(id, None) => {
+                trace!("read out body EOF; conn={}; id={:?}", self.conn, id);
+                if let Some(ref mut flow) = self.flow {
+                    flow.close_recv(id);
+                }
+
+                if let Some(body) = self.out_bodies.get_mut(&id) {
+                    body.eof = true;
+                }
+
+                self.flush_out_body(id);
             }
-            Frame::Body(_id, Some(_chunk)) => {
-                unimplemented!();
+            Frame::WindowUpdate(id, credit) => {
+                trace!("read window update; conn={}; id={:?}; credit={}", self.conn, id, credit);
+
+                // Ignored unless flow control is enabled
+                if let Some(ref mut flow) = self.flow {
+                    flow.window_update(id, credit);
+                }
             }
-            Frame::Body(_id, None) => {
-                unimplemented!();
+            Frame::Ping => {
+                trace!("read Frame::Ping; conn={}", self.conn);
+                self.pong_pending = true;
+            }
+            Frame::Pong => {
+                // Reading the frame is all the keepalive needs
+                trace!("read Frame::Pong; conn={}", self.conn);
+            }
+            Frame::GoAway => {
+                // The peer is expected to follow with `Frame::Done`
+                trace!("read Frame::GoAway; conn={}", self.conn);
             }
             Frame::Done => {
-                trace!("read Frame::Done");
+                trace!("read Frame::Done; conn={}", self.conn);
                 // At this point, we just return. This works
                 // because tick() will be called again and go
                 // through the read-cycle again.
@@ -210,12 +451,134 @@ impl<S, T, E> Multiplex<S, T>
         Ok(())
     }
 
+    fn dispatch_out_message(&mut self, id: RequestId, out_message: T::Out) {
+        self.schedule.dispatched(id, &out_message);
+
+        if self.dispatch.is_ready() {
+
+            // Only should be here if there are no queued messages
+            assert!(self.dispatch_deque.is_empty());
+
+            if let Err(_) = self.dispatch.dispatch(id, out_message) {
+                // TODO: Should dispatch be infalliable
+                unimplemented!();
+            }
+        } else {
+            trace!("dispatch not ready, queuing message; conn={}; id={:?}", self.conn, id);
+            // Queue the dispatch buffer
+            self.dispatch_deque.push(Frame::Message(id, out_message));
+        }
+    }
+
+    fn write_keepalive_frames(&mut self) -> io::Result<()> {
+        if self.pong_pending && self.transport.poll_write().is_ready() {
+            trace!("writing Frame::Pong; conn={}", self.conn);
+            try!(self.transport.write(Frame::Pong));
+            self.observer.frame_written();
+            self.pong_pending = false;
+        }
+
+        // Once no more frames are read, the peer's answers would go unnoticed
+        if !self.run && self.keepalive.is_some() {
+            trace!("done reading, disabling keepalive; conn={}", self.conn);
+            self.keepalive = None;
+        }
+
+        let ping = match self.keepalive {
+            Some(ref mut keepalive) => {
+                match keepalive.poll() {
+                    Ok(ping) => ping,
+                    Err(e) => {
+                        debug!("keepalive timed out, closing connection; conn={}", self.conn);
+                        return Err(e);
+                    }
+                }
+            }
+            None => false,
+        };
+
+        if ping && self.transport.poll_write().is_ready() {
+            trace!("writing Frame::Ping; conn={}", self.conn);
+            try!(self.transport.write(Frame::Ping));
+            self.observer.frame_written();
+
+            if let Some(ref mut keepalive) = self.keepalive {
+                try!(keepalive.ping_sent());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_goaway(&mut self) -> io::Result<()> {
+        if self.goaway_pending && self.transport.poll_write().is_ready() {
+            trace!("writing Frame::GoAway; conn={}", self.conn);
+            try!(self.transport.write(Frame::GoAway));
+            self.observer.frame_written();
+            self.goaway_pending = false;
+        }
+
+        Ok(())
+    }
+
     fn write_in_frames(&mut self) -> io::Result<()> {
+        // Body streams may have chunks available again
+        for id in self.blocked_bodies.drain(..) {
+            self.schedule.ready(id);
+        }
+
+        if let Some(ref mut flow) = self.flow {
+            // The peer may have granted credit to body streams waiting for it
+            {
+                let schedule = &mut self.schedule;
+
+                self.window_blocked.retain(|&id| {
+                    if flow.can_send(id) {
+                        schedule.ready(id);
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+
+            // Grant credit for the consumed request body chunks
+            while self.transport.poll_write().is_ready() {
+                match flow.next_update() {
+                    Some((id, credit)) => {
+                        trace!("writing window update; conn={}; id={:?}; credit={}", self.conn, id, credit);
+                        try!(self.transport.write(Frame::WindowUpdate(id, credit)));
+                        self.observer.frame_written();
+                    }
+                    None => break,
+                }
+            }
+        }
+
         while self.transport.poll_write().is_ready() {
-            // Write the next in-flight in message
-            match self.dispatch.poll() {
-                Some((id, msg)) => try!(self.write_in_message(id, msg)),
+            // Queue completed responses, so that the schedule decides the
+            // order they are written in. Responses are left with the dispatch
+            // while the transport is not writable, so that they count towards
+            // its in-flight limit.
+            while self.responses.len() < MAX_BUFFERED_FRAMES {
+                match self.dispatch.poll() {
+                    Some((id, msg)) => {
+                        self.responses.insert(id, msg);
+                        self.schedule.ready(id);
+                    }
+                    None => break,
+                }
+            }
+
+            let id = match self.schedule.next() {
+                Some(id) => id,
                 None => break,
+            };
+
+            // Either the response is not written yet, or its body is
+            match self.responses.remove(&id) {
+                Some(msg) => try!(self.write_in_message(id, msg)),
+                None => try!(self.write_in_body(id)),
             }
         }
 
@@ -223,17 +586,117 @@ impl<S, T, E> Multiplex<S, T>
     }
 
     fn write_in_message(&mut self, id: RequestId, message: Result<Message<S::InMsg, S::InBodyStream>, S::Error>) -> io::Result<()> {
-        match message {
+        let (val, body) = match message {
             Ok(Message::WithoutBody(val)) => {
-                trace!("got in_flight value with body");
-                try!(self.transport.write(Frame::Message(id, val)));
+                trace!("writing in message; conn={}; id={:?}", self.conn, id);
+                (val, None)
             }
-            Ok(Message::WithBody(_val, _body)) => {
-                unimplemented!();
+            Ok(Message::WithBody(val, body)) => {
+                trace!("writing in message with body; conn={}; id={:?}", self.conn, id);
+                (val, Some(body))
             }
             Err(e) => {
-                trace!("got in_flight error");
+                trace!("writing in error; conn={}; id={:?}", self.conn, id);
                 try!(self.transport.write(Frame::Error(id, e)));
+                self.observer.frame_written();
+                self.schedule.completed(id);
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = self.transport.write(Frame::Message(id, val)) {
+            // Only this message failed to be written, the transport is still
+            // usable. Respond with an error instead, dropping the body.
+            debug!("failed to write in message; conn={}; id={:?}; err={:?}", self.conn, id, e);
+            let err: Error<E> = Error::Io(e);
+            try!(self.transport.write(Frame::Error(id, err.into())));
+            self.observer.frame_written();
+            self.schedule.completed(id);
+            return Ok(());
+        }
+
+        self.observer.frame_written();
+
+        match body {
+            Some(body) => {
+                // Write the body as it becomes available
+                if let Some(ref mut flow) = self.flow {
+                    flow.open_send(id);
+                }
+
+                self.in_bodies.insert(id, body);
+                self.schedule.ready(id);
+            }
+            None => self.schedule.completed(id),
+        }
+
+        Ok(())
+    }
+
+    fn write_in_body(&mut self, id: RequestId) -> io::Result<()> {
+        if !self.in_bodies.contains_key(&id) {
+            return Ok(());
+        }
+
+        if let Some(ref flow) = self.flow {
+            if !flow.can_send(id) {
+                // Wait for the peer to grant credit before polling the body
+                trace!("in body stream out of credit; conn={}; id={:?}", self.conn, id);
+                self.window_blocked.push(id);
+                return Ok(());
+            }
+        }
+
+        let res = match self.in_bodies.get_mut(&id) {
+            Some(body) => body.poll(),
+            None => unreachable!(),
+        };
+
+        match res {
+            Ok(Async::Ready(Some(chunk))) => {
+                trace!("writing in body chunk; conn={}; id={:?}", self.conn, id);
+                try!(self.transport.write(Frame::Body(id, Some(chunk))));
+
+                if let Some(ref mut flow) = self.flow {
+                    flow.send(id);
+                }
+
+                self.observer.frame_written();
+                self.observer.body_chunk_sent();
+
+                self.schedule.ready(id);
+            }
+            Ok(Async::Ready(None)) => {
+                trace!("writing in body EOF; conn={}; id={:?}", self.conn, id);
+                try!(self.transport.write(Frame::Body(id, None)));
+                self.observer.frame_written();
+
+                self.in_bodies.remove(&id);
+
+                if let Some(ref mut flow) = self.flow {
+                    flow.close_send(id);
+                }
+
+                self.schedule.completed(id);
+            }
+            Err(e) => {
+                // The message head has already been written, the error
+                // terminates the response.
+                debug!("in body stream failed; conn={}; id={:?}", self.conn, id);
+                try!(self.transport.write(Frame::Error(id, e)));
+                self.observer.frame_written();
+
+                self.in_bodies.remove(&id);
+
+                if let Some(ref mut flow) = self.flow {
+                    flow.close_send(id);
+                }
+
+                self.schedule.completed(id);
+            }
+            Ok(Async::NotReady) => {
+                trace!("in body stream not ready; conn={}; id={:?}", self.conn, id);
+                self.blocked_bodies.push(id);
             }
         }
 
@@ -242,10 +705,26 @@ impl<S, T, E> Multiplex<S, T>
 
     fn flush(&mut self) -> io::Result<()> {
         self.is_flushed = try!(self.transport.flush()).is_ready();
+
+        if !self.is_flushed {
+            trace!("transport not flushed; conn={}", self.conn);
+            self.observer.flush_blocked();
+        }
+
         Ok(())
     }
 }
 
+impl<S, T> Drop for Multiplex<S, T>
+    where T: Transport,
+          S: Dispatch,
+{
+    fn drop(&mut self) {
+        trace!("connection closed; conn={}", self.conn);
+        self.observer.connection_closed();
+    }
+}
+
 impl<S, T, E> Future for Multiplex<S, T>
     where T: Transport<Error = E>,
           S: Dispatch<InMsg = T::In, InBody = T::BodyIn, OutMsg = T::Out, Error = E>,
@@ -256,17 +735,28 @@ impl<S, T, E> Future for Multiplex<S, T>
 
     // Tick the pipeline state machine
     fn poll(&mut self) -> Poll<(), io::Error> {
-        trace!("Multiplex::tick");
+        trace!("Multiplex::tick; conn={}", self.conn);
 
         // Always flush the transport first
         try!(self.flush());
 
+        // Check if the connection was told to drain
+        self.poll_drain();
+
         // Next try to dispatch any buffered messages
         try!(self.flush_dispatch_deque());
 
         // First read off data from the socket
         try!(self.read_out_frames());
 
+        // Answer pings, and ping the peer if the connection is idle. In-flight
+        // requests are dropped along with the connection if the peer does not
+        // answer.
+        try!(self.write_keepalive_frames());
+
+        // Tell the peer to migrate as soon as draining starts
+        try!(self.write_goaway());
+
         // Handle completed responses
         try!(self.write_in_frames());
 
@@ -301,54 +791,56 @@ impl<S, T, E> Future for Multiplex<S, T>
 
 /*
  *
- * ===== BusySender =====
+ * ===== OutBody =====
  *
  */
 
-/*
-impl<B, E> BodySender<B, E> {
-    fn flush(&mut self) -> bool {
-        let ready_sender;
+impl<B, E> OutBody<B, E> {
+    // Sends buffered chunks to the body stream as it consumes them. Returns
+    // the number of chunks consumed, and whether the body is done.
+    //
+    // A chunk is only accepted by the sender once the body stream took the
+    // previous one, so that the chunk is counted as consumed then.
+    fn flush(&mut self) -> (usize, bool) {
+        let mut consumed = 0;
 
-        // Attempt to flush frames as long as the sender is ready
         loop {
-            if let BodySender::Busy(ref mut busy, ref mut frames) = *self {
-                match busy.poll() {
-                    Ok(Async::Ready(sender)) => {
-                        // Now, if there is a pending frame to send.. send it
-                        match frames.pop() {
-                            Some(Some(chunk)) => {
-                                let b = sender.send(Ok(chunk));
-                                *busy = b;
-                            }
-                            Some(None) => {
-                                // Done sending the body chunks, drop the
-                                // sender
-                                return true;
-                            }
-                            None => {
-                                ready_sender = sender;
-                                break;
-                            }
+            let sender = match self.sender.take() {
+                Some(BodySender::Ready(sender)) => sender,
+                Some(BodySender::Busy(mut busy)) => {
+                    match busy.poll() {
+                        Ok(Async::Ready(sender)) => {
+                            consumed += 1;
+                            sender
+                        }
+                        Ok(Async::NotReady) => {
+                            self.sender = Some(BodySender::Busy(busy));
+                            return (consumed, false);
+                        }
+                        Err(_) => {
+                            // The receiving end dropped interest in the body
+                            // stream, the buffered chunks are dropped along
+                            // with the sender.
+                            return (consumed + 1 + self.chunks.len(), true);
                         }
                     }
-                    Ok(Async::NotReady) => {},
-                    Err(_) => {
-                        // The receiving end dropped interest in the body
-                        // stream. In this case, the sender and the frame
-                        // buffer is dropped. If future body frames are
-                        // received, the sender will be gone and the frames
-                        // will be dropped.
-                        return true;
+                }
+                None => return (consumed + self.chunks.len(), true),
+            };
+
+            match self.chunks.pop() {
+                Some(chunk) => {
+                    self.sender = Some(BodySender::Busy(sender.send(Ok(chunk))));
+                }
+                None => {
+                    // Dropping the sender terminates the body stream
+                    if !self.eof {
+                        self.sender = Some(BodySender::Ready(sender));
                     }
+
+                    return (consumed, self.eof);
                 }
-            } else {
-                return false;
             }
-        };
-
-        *self = BodySender::Ready(ready_sender);
-        false
+        }
     }
 }
-*/
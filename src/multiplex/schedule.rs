@@ -0,0 +1,181 @@
+use super::RequestId;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+
+/// Decides the order in which responses are written to the transport
+///
+/// The multiplexer writes one frame at a time. A response becomes ready when
+/// the service completes it, and again after each of its frames is written as
+/// long as it has a body left to write. Before writing a frame, the
+/// multiplexer calls `next` to pick the response the frame is taken from.
+///
+/// `Fifo` is used by default.
+pub trait Schedule<T> {
+    /// Called when request `id` is read from the transport, before it is
+    /// dispatched to the service.
+    fn dispatched(&mut self, id: RequestId, request: &T) {
+        let _ = (id, request);
+    }
+
+    /// The response to request `id` has a frame ready to be written.
+    fn ready(&mut self, id: RequestId);
+
+    /// Returns the request the next frame should be written for, removing it
+    /// from the ready responses.
+    fn next(&mut self) -> Option<RequestId>;
+
+    /// The response to request `id` has been fully written.
+    fn completed(&mut self, id: RequestId) {
+        let _ = id;
+    }
+}
+
+/// Writes responses in the order they complete
+///
+/// Once a response is started, its body is written to completion before the
+/// next response is started, unless the body has no chunk available.
+#[derive(Debug, Default)]
+pub struct Fifo {
+    ready: VecDeque<RequestId>,
+    // The response most recently returned by `next`
+    current: Option<RequestId>,
+}
+
+/// Takes turns writing a single frame of each ready response
+///
+/// Responses with large bodies are interleaved with other responses instead
+/// of delaying them until the body is fully written.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    ready: VecDeque<RequestId>,
+}
+
+/// Takes turns writing frames of each ready response, proportionally to a
+/// weight taken from the request
+///
+/// A response with a weight of 4 gets 4 frames written for every frame of a
+/// response with a weight of 1. Weights of 0 are treated as 1, so that no
+/// response is starved.
+pub struct Weighted<F> {
+    weight: F,
+    // Weights of the requests in flight
+    weights: HashMap<RequestId, u32>,
+    ready: VecDeque<RequestId>,
+    // The response most recently returned by `next`, along with the number of
+    // frames left in its turn
+    current: Option<(RequestId, u32)>,
+}
+
+/*
+ *
+ * ===== impl Fifo =====
+ *
+ */
+
+impl Fifo {
+    /// Create a new `Fifo` schedule
+    pub fn new() -> Fifo {
+        Fifo::default()
+    }
+}
+
+impl<T> Schedule<T> for Fifo {
+    fn ready(&mut self, id: RequestId) {
+        if self.current == Some(id) {
+            // Continue with the current response
+            self.ready.push_front(id);
+        } else {
+            self.ready.push_back(id);
+        }
+    }
+
+    fn next(&mut self) -> Option<RequestId> {
+        self.current = self.ready.pop_front();
+        self.current
+    }
+}
+
+/*
+ *
+ * ===== impl RoundRobin =====
+ *
+ */
+
+impl RoundRobin {
+    /// Create a new `RoundRobin` schedule
+    pub fn new() -> RoundRobin {
+        RoundRobin::default()
+    }
+}
+
+impl<T> Schedule<T> for RoundRobin {
+    fn ready(&mut self, id: RequestId) {
+        self.ready.push_back(id);
+    }
+
+    fn next(&mut self) -> Option<RequestId> {
+        self.ready.pop_front()
+    }
+}
+
+/*
+ *
+ * ===== impl Weighted =====
+ *
+ */
+
+impl<F> Weighted<F> {
+    /// Create a new `Weighted` schedule, using `weight` to compute the weight
+    /// of each request.
+    pub fn new(weight: F) -> Weighted<F> {
+        Weighted {
+            weight: weight,
+            weights: HashMap::new(),
+            ready: VecDeque::new(),
+            current: None,
+        }
+    }
+
+    fn weight_of(&self, id: RequestId) -> u32 {
+        let weight = self.weights.get(&id).cloned().unwrap_or(1);
+        cmp::max(weight, 1)
+    }
+}
+
+impl<T, F> Schedule<T> for Weighted<F>
+    where F: Fn(&T) -> u32,
+{
+    fn dispatched(&mut self, id: RequestId, request: &T) {
+        let weight = (self.weight)(request);
+        self.weights.insert(id, weight);
+    }
+
+    fn ready(&mut self, id: RequestId) {
+        match self.current {
+            // The turn of the current response is not over yet
+            Some((current, left)) if current == id && left > 0 => {
+                self.ready.push_front(id);
+            }
+            _ => self.ready.push_back(id),
+        }
+    }
+
+    fn next(&mut self) -> Option<RequestId> {
+        let id = match self.ready.pop_front() {
+            Some(id) => id,
+            None => return None,
+        };
+
+        let left = match self.current {
+            Some((current, left)) if current == id && left > 0 => left,
+            _ => self.weight_of(id),
+        };
+
+        self.current = Some((id, left - 1));
+        Some(id)
+    }
+
+    fn completed(&mut self, id: RequestId) {
+        self.weights.remove(&id);
+    }
+}
@@ -1,6 +1,15 @@
-use super::{multiplex, RequestId, Error, Message, ServerService, Transport};
+use super::{multiplex, RequestId, Error, Message, Schedule, ServerService, Transport};
+use context::RequestContext;
+use in_flight::InFlightSet;
+use registry::{self, Registration, Registry};
+use server::{ConnectionState, Drain};
+use metrics::Observer;
 use futures::{Future, Poll, Async};
+use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
 
 /// A server `Task` that dispatches `Transport` messages to a `Service` using
 /// protocol multiplexing.
@@ -9,12 +18,26 @@ pub struct Server<S, T>
           T: Transport,
 {
     inner: multiplex::Multiplex<Dispatch<S>, T>,
+    // Entry in the registry the connection was added to, see `set_registry`
+    registration: Option<Registration>,
 }
 
 struct Dispatch<S: ServerService> {
     // The service handling the connection
     service: S,
-    in_flight: Vec<(RequestId, InFlight<S::Future>)>,
+    // In-flight requests, along with the id, context of, and the time each
+    // was dispatched
+    in_flight: InFlightSet<(RequestId, RequestContext, Instant, InFlight<S::Future>)>,
+    // Slots of the in-flight requests, by request id
+    slots: HashMap<RequestId, usize>,
+    // Slots of the in-flight requests whose response is complete
+    done: VecDeque<usize>,
+    // Notified of request events
+    observer: Arc<Observer>,
+    // Identifies the connection in request contexts
+    conn: usize,
+    // Extracts the trace context from requests
+    trace_extractor: Option<Box<Fn(&S::Request) -> Option<String> + Send>>,
 }
 
 enum InFlight<F: Future> {
@@ -41,14 +64,115 @@ impl<T, S, E> Server<S, T>
     pub fn new(service: S, transport: T) -> io::Result<Server<S, T>> {
         let dispatch = Dispatch {
             service: service,
-            in_flight: vec![],
+            in_flight: InFlightSet::new(),
+            slots: HashMap::new(),
+            done: VecDeque::new(),
+            observer: Arc::new(()),
+            conn: 0,
+            trace_extractor: None,
         };
 
         // Create the multiplexer
-        let multiplex = try!(multiplex::Multiplex::new(dispatch, transport));
+        let mut multiplex = try!(multiplex::Multiplex::new(dispatch, transport));
+
+        // Requests are identified by the connection id assigned by the
+        // multiplexer
+        let conn = multiplex.connection_id();
+        multiplex.dispatch_mut().conn = conn;
 
         // Return the server task
-        Ok(Server { inner: multiplex })
+        Ok(Server {
+            inner: multiplex,
+            registration: None,
+        })
+    }
+
+    /// Returns a snapshot of the state of the connection
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state()
+    }
+
+    /// Returns a handle used to drain the connection
+    ///
+    /// See `Drain` for more details.
+    pub fn drain_handle(&self) -> Drain {
+        self.inner.drain_handle()
+    }
+
+    /// Adds the connection to `registry`, removing it from any registry it
+    /// was previously added to
+    ///
+    /// The connection is listed by the registry until the server is dropped.
+    /// See `Registry` for more details.
+    pub fn set_registry(&mut self, registry: &Registry) {
+        let state = self.inner.state();
+        let drain = self.inner.drain_handle();
+
+        // Drop the previous registration first, removing its entry
+        self.registration = None;
+        self.registration = Some(registry::register(registry, state, drain));
+    }
+
+    /// Sets the observer notified of events on this connection
+    ///
+    /// See the `metrics` module for more details.
+    pub fn set_observer(&mut self, observer: Arc<Observer>) {
+        self.inner.dispatch_mut().observer = observer.clone();
+        self.inner.set_observer(observer);
+    }
+
+    /// Sets the schedule deciding the order in which response frames are
+    /// written
+    ///
+    /// See the `Schedule` trait for more details.
+    pub fn set_schedule<U>(&mut self, schedule: U)
+        where U: Schedule<S::Request> + Send + 'static,
+    {
+        self.inner.set_schedule(Box::new(schedule));
+    }
+
+    /// Enables flow control, with the given initial windows of each body
+    /// stream and of the connection
+    ///
+    /// Windows are frame credits: each body frame consumes one credit,
+    /// whatever the size of its chunk. The peer must be configured with the
+    /// same windows, and the `Transport` must be able to encode and decode
+    /// `Frame::WindowUpdate`. Flow control is disabled by default. See the
+    /// module docs for more details.
+    ///
+    /// Returns an error if either window is zero, or if the connection window
+    /// is too large for its frames to be buffered, which is over a million
+    /// frames.
+    ///
+    /// # Panics
+    ///
+    /// Panics if bodies already started streaming on the connection.
+    pub fn set_flow_control_windows(&mut self, stream: usize, connection: usize) -> io::Result<()> {
+        self.inner.set_flow_control_windows(stream, connection)
+    }
+
+    /// Enables keepalive pings on the connection
+    ///
+    /// See `pipeline::Server::set_keepalive` for more details.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration, handle: &Handle) {
+        self.inner.set_keepalive(interval, timeout, handle);
+    }
+
+    /// Sets whether a `Frame::GoAway` is written when the connection starts
+    /// draining
+    ///
+    /// See `pipeline::Server::set_goaway` for more details.
+    pub fn set_goaway(&mut self, enabled: bool) {
+        self.inner.set_goaway(enabled);
+    }
+
+    /// Sets the function used to extract a trace context from requests
+    ///
+    /// See `pipeline::Server::set_trace_extractor` for more details.
+    pub fn set_trace_extractor<F>(&mut self, extract: F)
+        where F: Fn(&S::Request) -> Option<String> + Send + 'static,
+    {
+        self.inner.dispatch_mut().trace_extractor = Some(Box::new(extract));
     }
 }
 
@@ -62,25 +186,45 @@ impl<S> multiplex::Dispatch for Dispatch<S>
     type Error = S::Error;
 
     fn dispatch(&mut self, request_id: RequestId, request: Self::OutMsg) -> io::Result<()> {
-        let response = self.service.call(request);
-        self.in_flight.push((request_id, InFlight::Active(response)));
+        let trace_id = self.trace_extractor.as_ref().and_then(|extract| extract(&request));
+        let cx = RequestContext::new(self.conn, request_id, trace_id);
+
+        trace!("dispatching request; conn={}; id={:?}; trace_id={:?}",
+               self.conn, request_id, cx.trace_id());
+
+        let service = &mut self.service;
+        let response = cx.enter(|| service.call(request));
+
+        let slot = self.in_flight.insert((request_id, cx, Instant::now(), InFlight::Active(response)));
+
+        if let Some(prev) = self.slots.insert(request_id, slot) {
+            // The peer reused the id of a request it is still waiting on, the
+            // previous response can no longer be told apart
+            debug!("dropping response to reused request id; conn={}; id={:?}", self.conn, request_id);
+            self.in_flight.remove(prev);
+            self.done.retain(|&done| done != prev);
+        }
+
+        self.observer.request_dispatched(self.in_flight.len());
         Ok(())
     }
 
     fn poll(&mut self) -> Option<(RequestId, Result<Message<Self::InMsg, Self::InBodyStream>, Self::Error>)> {
-        trace!("Dispatch::poll");
+        let done = &mut self.done;
 
-        let mut idx = None;
-
-        for (i, &mut (request_id, ref mut slot)) in self.in_flight.iter_mut().enumerate() {
-            trace!("   --> poll; request_id={:?}", request_id);
-            if slot.poll() && idx.is_none() {
-                idx = Some(i);
+        // Only poll the responses that were notified
+        self.in_flight.poll_ready(|slot, &mut (request_id, ref cx, _, ref mut response)| {
+            trace!("polling response; conn={}; id={:?}", cx.connection_id(), request_id);
+            if cx.enter(|| response.poll()) {
+                done.push_back(slot);
             }
-        }
+        });
 
-        if let Some(idx) = idx {
-            let (request_id, msg) = self.in_flight.remove(idx);
+        if let Some(slot) = done.pop_front() {
+            let (request_id, cx, started, msg) = self.in_flight.remove(slot);
+            self.slots.remove(&request_id);
+            trace!("response complete; conn={}; id={:?}", cx.connection_id(), request_id);
+            self.observer.response_completed(started.elapsed());
             Some((request_id, msg.unwrap_done()))
         } else {
             None
@@ -91,8 +235,8 @@ impl<S> multiplex::Dispatch for Dispatch<S>
         self.in_flight.len() < MAX_IN_FLIGHT_REQUESTS
     }
 
-    fn has_in_flight(&self) -> bool {
-        !self.in_flight.is_empty()
+    fn in_flight(&self) -> usize {
+        self.in_flight.len()
     }
 }
 
@@ -105,7 +249,13 @@ impl<T, S, E> Future for Server<S, T>
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<(), io::Error> {
-        self.inner.poll()
+        let ret = self.inner.poll();
+
+        if let Some(ref mut registration) = self.registration {
+            registration.update(self.inner.state());
+        }
+
+        ret
     }
 }
 
@@ -116,18 +266,18 @@ impl<T, S, E> Future for Server<S, T>
  */
 
 impl<F: Future> InFlight<F> {
-    // Returns true if done
+    // Returns true if the future completed
     fn poll(&mut self) -> bool {
         let res = match *self {
             InFlight::Active(ref mut f) => {
-                trace!("   --> polling future");
                 match f.poll() {
                     Ok(Async::Ready(e)) => Ok(e),
                     Err(e) => Err(e),
                     Ok(Async::NotReady) => return false,
                 }
             }
-            _ => return true,
+            // Already completed
+            _ => return false,
         };
 
         *self = InFlight::Done(res);
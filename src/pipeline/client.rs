@@ -1,11 +1,14 @@
 use std::collections::VecDeque;
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::stream::Stream;
 use futures::{self, Future, BoxFuture, Complete, Async};
 use tokio_core::reactor::Handle;
 use tokio_core::channel::{channel, Sender, Receiver};
 
+use metrics::Observer;
 use tokio_service::Service;
 use super::{pipeline, Error, Message, Transport, NewTransport};
 
@@ -23,10 +26,32 @@ struct Dispatch<T, B, E>
           E: From<Error<E>>,
 {
     requests: Receiver<(Message<T::In, B>, Complete<Result<T::Out, E>>)>,
-    in_flight: VecDeque<Complete<Result<T::Out, E>>>,
+    // Requests written to the transport, along with the time each was
+    // written
+    in_flight: VecDeque<(Instant, Complete<Result<T::Out, E>>)>,
+    // Notified of request events
+    observer: Arc<Observer>,
+}
+
+/// Configures and connects pipeline clients.
+///
+/// ```rust,ignore
+/// let client = try!(ClientBuilder::new()
+///     .observer(observer)
+///     .keepalive(Duration::from_secs(30), Duration::from_secs(10))
+///     .connect(&handle, new_transport));
+/// ```
+#[derive(Clone)]
+pub struct ClientBuilder {
+    // Notified of events on each connection
+    observer: Arc<Observer>,
+    // Ping interval and timeout
+    keepalive: Option<(Duration, Duration)>,
 }
 
 /// Connect to the given `addr` and handle using the given Transport and protocol pipelining.
+///
+/// Use `ClientBuilder` to configure the client.
 pub fn connect<T, B, E>(handle: &Handle, new_transport: T)
                         -> io::Result<Client<T::In, T::Out, B, E>>
     where T: NewTransport<Error = E> + Send + 'static,
@@ -35,25 +60,75 @@ pub fn connect<T, B, E>(handle: &Handle, new_transport: T)
           B: Stream<Item = T::BodyIn, Error = E> + Send + 'static,
           E: From<Error<E>> + Send + 'static,
 {
-    let (tx, rx) = try!(channel(handle));
+    ClientBuilder::new().connect(handle, new_transport)
+}
 
-    // Create the transport
-    let transport = try!(new_transport.new_transport());
+impl ClientBuilder {
+    /// Create a new `ClientBuilder` with the default configuration
+    pub fn new() -> ClientBuilder {
+        ClientBuilder {
+            observer: Arc::new(()),
+            keepalive: None,
+        }
+    }
+
+    /// Notify `observer` of events on the connection.
+    ///
+    /// See the `metrics` module for more details.
+    pub fn observer(mut self, observer: Arc<Observer>) -> ClientBuilder {
+        self.observer = observer;
+        self
+    }
+
+    /// Ping the peer once no frame was read from the connection for
+    /// `interval`.
+    ///
+    /// If no frame is read within `timeout` of the ping, the connection is
+    /// closed and the pending requests fail with a broken pipe error. The
+    /// `Transport` must be able to encode `Frame::Ping` and decode
+    /// `Frame::Pong`. See `Server::set_keepalive` for more details.
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> ClientBuilder {
+        self.keepalive = Some((interval, timeout));
+        self
+    }
 
-    // Create the client dispatch
-    let dispatch: Dispatch<T::Item, B, E> = Dispatch {
-        requests: rx,
-        in_flight: VecDeque::with_capacity(32),
-    };
+    /// Connect using the given Transport, spawning the connection's task on
+    /// `handle`.
+    pub fn connect<T, B, E>(&self, handle: &Handle, new_transport: T)
+                            -> io::Result<Client<T::In, T::Out, B, E>>
+        where T: NewTransport<Error = E> + Send + 'static,
+              T::In: Send + 'static,
+              T::Out: Send + 'static,
+              B: Stream<Item = T::BodyIn, Error = E> + Send + 'static,
+              E: From<Error<E>> + Send + 'static,
+    {
+        let (tx, rx) = try!(channel(handle));
+
+        // Create the transport
+        let transport = try!(new_transport.new_transport());
+
+        // Create the client dispatch
+        let dispatch: Dispatch<T::Item, B, E> = Dispatch {
+            requests: rx,
+            in_flight: VecDeque::with_capacity(32),
+            observer: self.observer.clone(),
+        };
+
+        // Create the pipeline with the dispatch and transport
+        let mut pipeline = try!(pipeline::Pipeline::new(dispatch, transport));
+        pipeline.set_observer(self.observer.clone());
+
+        if let Some((interval, timeout)) = self.keepalive {
+            pipeline.set_keepalive(interval, timeout, handle);
+        }
 
-    // Create the pipeline with the dispatch and transport
-    let pipeline = try!(pipeline::Pipeline::new(dispatch, transport));
-    handle.spawn(pipeline.map_err(|e| {
-        // TODO: where to punt this error to?
-        error!("pipeline error: {}", e)
-    }));
+        handle.spawn(pipeline.map_err(|e| {
+            // TODO: where to punt this error to?
+            error!("pipeline error: {}", e)
+        }));
 
-    Ok(Client { tx: tx })
+        Ok(Client { tx: tx })
+    }
 }
 
 impl<Req, Resp, ReqBody, E> Service for Client<Req, Resp, ReqBody, E>
@@ -102,7 +177,8 @@ impl<T, B, E> pipeline::Dispatch for Dispatch<T, B, E>
     type Error = E;
 
     fn dispatch(&mut self, response: Self::OutMsg) -> io::Result<()> {
-        if let Some(complete) = self.in_flight.pop_front() {
+        if let Some((started, complete)) = self.in_flight.pop_front() {
+            self.observer.response_completed(started.elapsed());
             complete.complete(Ok(response));
         } else {
             return Err(io::Error::new(io::ErrorKind::Other, "request / response mismatch"));
@@ -118,7 +194,8 @@ impl<T, B, E> pipeline::Dispatch for Dispatch<T, B, E>
                 trace!("received request");
 
                 // Track complete handle
-                self.in_flight.push_back(complete);
+                self.in_flight.push_back((Instant::now(), complete));
+                self.observer.request_dispatched(self.in_flight.len());
 
                 Some(Ok(request))
 
@@ -134,8 +211,24 @@ impl<T, B, E> pipeline::Dispatch for Dispatch<T, B, E>
         }
     }
 
-    fn has_in_flight(&self) -> bool {
-        !self.in_flight.is_empty()
+    fn write_failed(&mut self, err: io::Error) -> Option<Self::Error> {
+        // The request was never sent, so the peer does not expect anything.
+        // Fail the request locally.
+        if let Some((_, complete)) = self.in_flight.pop_back() {
+            let err = Error::Io(err);
+            complete.complete(Err(err.into()));
+        }
+
+        None
+    }
+
+    fn is_ready(&self) -> bool {
+        // Responses only complete requests already in flight
+        true
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.len()
     }
 }
 
@@ -146,7 +239,7 @@ impl<T, B, E> Drop for Dispatch<T, B, E>
 {
     fn drop(&mut self) {
         // Complete any pending requests with an error
-        while let Some(complete) = self.in_flight.pop_front() {
+        while let Some((_, complete)) = self.in_flight.pop_front() {
             let err = Error::Io(broken_pipe());
             complete.complete(Err(err.into()));
         }
@@ -23,12 +23,21 @@
 //! that reads and writes `Frame` messages. It operates on the transport
 //! following the rules of pipelining as described above and exposes the
 //! protocol using a `Service`.
+//!
+//! # Keepalive
+//!
+//! Connections through NATs and load balancers may be dropped silently when
+//! idle. `Server::set_keepalive` and `ClientBuilder::keepalive` make the
+//! dispatcher write `Frame::Ping` when the connection is idle and close it if
+//! the peer does not answer. A `Frame::Ping` read from the transport is always
+//! answered with a `Frame::Pong`. How these frames are encoded is up to the
+//! `Transport`.
 
 mod client;
 mod server;
 mod pipeline;
 
-pub use self::client::{connect, Client};
+pub use self::client::{connect, Client, ClientBuilder};
 pub use self::server::Server;
 
 use tokio_core::io::FramedIo;
@@ -51,6 +60,16 @@ pub enum Frame<T, B, E> {
     Body(Option<B>),
     /// Error
     Error(E),
+    /// Keepalive ping. Written when keepalive is enabled and no frame was
+    /// read from the connection for a while. A `Ping` read from the transport
+    /// is answered with a `Pong`.
+    Ping,
+    /// Answer to a `Ping`
+    Pong,
+    /// Written when the connection starts draining, if enabled, telling the
+    /// peer to stop sending requests and migrate to another connection. See
+    /// `server::Drain`.
+    GoAway,
     /// Final frame sent in each transport direction
     Done,
 }
@@ -129,6 +148,10 @@ pub trait Transport {
     fn poll_write(&mut self) -> Async<()>;
 
     /// Write a message to the `Transport`
+    ///
+    /// An error returned when writing a `Message` frame only fails that
+    /// message, the dispatcher keeps using the transport. I/O errors should be
+    /// reported by `flush`.
     fn write(&mut self, req: Frame<Self::In, Self::BodyIn, Self::Error>) -> Poll<(), io::Error>;
 
     /// Flush pending writes to the socket
@@ -180,6 +203,9 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::MessageWithBody(v, _) => v,
             Frame::Body(..) => panic!("called `Frame::unwrap_msg()` on a `Body` value"),
             Frame::Error(..) => panic!("called `Frame::unwrap_msg()` on an `Error` value"),
+            Frame::Ping => panic!("called `Frame::unwrap_msg()` on a `Ping` value"),
+            Frame::Pong => panic!("called `Frame::unwrap_msg()` on a `Pong` value"),
+            Frame::GoAway => panic!("called `Frame::unwrap_msg()` on a `GoAway` value"),
             Frame::Done => panic!("called `Frame::unwrap_msg()` on a `Done` value"),
         }
     }
@@ -191,6 +217,9 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::Message(..) => panic!("called `Frame::unwrap_body()` on a `Message` value"),
             Frame::MessageWithBody(..) => panic!("called `Frame::unwrap_body()` on a `MessageWithBody` value"),
             Frame::Error(..) => panic!("called `Frame::unwrap_body()` on an `Error` value"),
+            Frame::Ping => panic!("called `Frame::unwrap_body()` on a `Ping` value"),
+            Frame::Pong => panic!("called `Frame::unwrap_body()` on a `Pong` value"),
+            Frame::GoAway => panic!("called `Frame::unwrap_body()` on a `GoAway` value"),
             Frame::Done => panic!("called `Frame::unwrap_body()` on a `Done` value"),
         }
     }
@@ -202,6 +231,9 @@ impl<T, B, E> Frame<T, B, E> {
             Frame::Body(..) => panic!("called `Frame::unwrap_err()` on a `Body` value"),
             Frame::Message(..) => panic!("called `Frame::unwrap_err()` on a `Message` value"),
             Frame::MessageWithBody(..) => panic!("called `Frame::unwrap_err()` on a `MessageWithBody` value"),
+            Frame::Ping => panic!("called `Frame::unwrap_err()` on a `Ping` value"),
+            Frame::Pong => panic!("called `Frame::unwrap_err()` on a `Pong` value"),
+            Frame::GoAway => panic!("called `Frame::unwrap_err()` on a `GoAway` value"),
             Frame::Done => panic!("called `Frame::unwrap_message()` on a `Done` value"),
         }
     }
@@ -226,6 +258,9 @@ impl<T, B, E> fmt::Debug for Frame<T, B, E>
             Frame::MessageWithBody(ref v, _) => write!(fmt, "Frame::MessageWithBody({:?}, Sender)", v),
             Frame::Body(ref v) => write!(fmt, "Frame::Body({:?})", v),
             Frame::Error(ref v) => write!(fmt, "Frame::Error({:?})", v),
+            Frame::Ping => write!(fmt, "Frame::Ping"),
+            Frame::Pong => write!(fmt, "Frame::Pong"),
+            Frame::GoAway => write!(fmt, "Frame::GoAway"),
             Frame::Done => write!(fmt, "Frame::Done"),
         }
     }
@@ -1,7 +1,15 @@
 use super::{Error, Frame, Message, Transport};
+use context;
+use drain::{self, Drain};
+use keepalive::Keepalive;
+use server::ConnectionState;
+use metrics::Observer;
 use futures::stream::{Stream, Sender, FutureSender};
 use futures::{Future, Poll, Async};
+use tokio_core::reactor::Handle;
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
 // TODO:
 //
@@ -26,6 +34,26 @@ pub struct Pipeline<S, T>
     is_flushed: bool,
     // Glues the service with the pipeline task
     dispatch: S,
+    // Notified of connection events
+    observer: Arc<Observer>,
+    // Identifies the connection in log events
+    conn: usize,
+    // Sequence number of the next out message read from the transport
+    read_seq: u64,
+    // Sequence number of the next in message written to the transport
+    write_seq: u64,
+    // Pings the peer when the connection is idle
+    keepalive: Option<Keepalive>,
+    // True when a ping was read and is yet to be answered
+    pong_pending: bool,
+    // Signals the connection to drain
+    drain: Drain,
+    // True once the connection started draining
+    draining: bool,
+    // True if `Frame::GoAway` is written when draining starts
+    goaway: bool,
+    // True when draining started and `Frame::GoAway` is yet to be written
+    goaway_pending: bool,
 }
 
 /// Dispatch messages from the transport to the service
@@ -49,8 +77,18 @@ pub trait Dispatch {
     /// Poll the next completed message
     fn poll(&mut self) -> Option<Result<Message<Self::InMsg, Self::InBodyStream>, Self::Error>>;
 
-    /// RPC currently in flight
-    fn has_in_flight(&self) -> bool;
+    /// Called when the transport failed to write the message most recently
+    /// returned by `poll`.
+    ///
+    /// Returns an error to write to the transport in place of the message, if
+    /// the peer expects one.
+    fn write_failed(&mut self, err: io::Error) -> Option<Self::Error>;
+
+    /// The `Dispatch` is ready to accept another message
+    fn is_ready(&self) -> bool;
+
+    /// Number of RPCs currently in flight
+    fn in_flight(&self) -> usize;
 }
 
 enum BodySender<B, E> {
@@ -73,44 +111,133 @@ impl<S, T, E> Pipeline<S, T>
             in_body: None,
             is_flushed: true,
             dispatch: dispatch,
+            observer: Arc::new(()),
+            conn: context::next_connection_id(),
+            read_seq: 0,
+            write_seq: 0,
+            keepalive: None,
+            pong_pending: false,
+            drain: drain::new(),
+            draining: false,
+            goaway: false,
+            goaway_pending: false,
         })
     }
 
+    /// Returns the id identifying this connection in log events
+    pub fn connection_id(&self) -> usize {
+        self.conn
+    }
+
+    /// Returns a snapshot of the state of the connection
+    pub fn state(&self) -> ConnectionState {
+        let out_body = match self.out_body {
+            Some(BodySender::Busy(..)) => true,
+            _ => false,
+        };
+
+        ConnectionState {
+            connection_id: self.conn,
+            in_flight: self.dispatch.in_flight(),
+            in_body: self.in_body.is_some(),
+            out_body: out_body,
+            // Messages are only read when the dispatch can accept them
+            dispatch_deque: 0,
+            is_flushed: self.is_flushed,
+            draining: self.draining,
+        }
+    }
+
+    /// Sets the observer notified of connection events
+    pub fn set_observer(&mut self, observer: Arc<Observer>) {
+        self.observer = observer;
+    }
+
+    /// Pings the peer once no frame was read for `interval`, closing the
+    /// connection if no frame is read within `timeout` of the ping
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration, handle: &Handle) {
+        self.keepalive = Some(Keepalive::new(interval, timeout, handle));
+    }
+
+    /// Sets whether `Frame::GoAway` is written when draining starts
+    pub fn set_goaway(&mut self, enabled: bool) {
+        self.goaway = enabled;
+    }
+
+    /// Returns a handle used to drain the connection
+    pub fn drain_handle(&self) -> Drain {
+        self.drain.clone()
+    }
+
+    /// Returns a mutable reference to the dispatch
+    pub fn dispatch_mut(&mut self) -> &mut S {
+        &mut self.dispatch
+    }
+
     /// Returns true if the pipeline server dispatch has nothing left to do
     fn is_done(&self) -> bool {
-        !self.run && self.is_flushed && !self.dispatch.has_in_flight()
+        !self.run && self.is_flushed && self.dispatch.in_flight() == 0 &&
+            self.in_body.is_none() && !self.goaway_pending
     }
 
-    fn read_out_frames(&mut self) -> io::Result<()> {
+    fn poll_drain(&mut self) {
+        if !self.draining && drain::poll(&self.drain) {
+            debug!("draining connection; conn={}; in_flight={}", self.conn, self.dispatch.in_flight());
+            self.draining = true;
+            self.goaway_pending = self.goaway;
+        }
+    }
+
+    // Returns true if reading stopped because the dispatch is at capacity
+    fn read_out_frames(&mut self) -> io::Result<bool> {
         while self.run {
+            // Once draining, only the body of the current request is read
+            if self.draining && self.out_body.is_none() {
+                trace!("connection draining, done reading; conn={}", self.conn);
+                self.run = false;
+                break;
+            }
+
             if !self.check_out_body_stream() {
+                // Answers to pings are not read either, the connection is
+                // busy rather than idle
+                self.keepalive_activity();
                 break;
             }
 
+            // Stop reading new messages once the dispatch is at capacity. The
+            // body of the current message is still read, as the response may
+            // depend on it.
+            if self.out_body.is_none() && !self.dispatch.is_ready() {
+                trace!("dispatch not ready; conn={}; in_flight={}", self.conn, self.dispatch.in_flight());
+                self.keepalive_activity();
+                return Ok(true);
+            }
+
             if let Async::Ready(frame) = try!(self.transport.read()) {
+                self.observer.frame_read();
+                self.keepalive_activity();
                 try!(self.process_out_frame(frame));
             } else {
                 break;
             }
         }
 
-        Ok(())
+        Ok(false)
     }
 
     fn check_out_body_stream(&mut self) -> bool {
         let sender = match self.out_body {
             Some(BodySender::Ready(..)) => {
-                debug!("ready for a send");
                 // The body sender is ready
                 return true;
             }
             Some(BodySender::Busy(ref mut busy)) => {
-                debug!("waiting to be ready to send again");
                 match busy.poll() {
                     Ok(Async::Ready(sender)) => sender,
                     Err(_) => unimplemented!(),
                     Ok(Async::NotReady) => {
-                        // Not ready
+                        trace!("out body stream full; conn={}; seq={}", self.conn, self.read_seq.wrapping_sub(1));
                         return false;
                     }
                 }
@@ -118,18 +245,19 @@ impl<S, T, E> Pipeline<S, T>
             None => return true,
         };
 
-        debug!("reading again for another send");
+        trace!("out body stream ready; conn={}; seq={}", self.conn, self.read_seq.wrapping_sub(1));
         self.out_body = Some(BodySender::Ready(sender));
         true
     }
 
     fn process_out_frame(&mut self, frame: Frame<T::Out, T::BodyOut, E>) -> io::Result<()> {
-        trace!("process_out_frame");
         // At this point, the service & transport are ready to process the
         // frame, no matter what it is.
         match frame {
             Frame::Message(out_message) => {
-                trace!("read out message");
+                trace!("read out message; conn={}; seq={}", self.conn, self.read_seq);
+                self.read_seq += 1;
+
                 // There is no streaming body. Set `out_body` to `None` so that
                 // the previous body stream is dropped.
                 self.out_body = None;
@@ -140,7 +268,9 @@ impl<S, T, E> Pipeline<S, T>
                 }
             }
             Frame::MessageWithBody(out_message, body_sender) => {
-                trace!("read out message with body");
+                trace!("read out message with body; conn={}; seq={}", self.conn, self.read_seq);
+                self.read_seq += 1;
+
                 // Track the out body sender. If `self.out_body`
                 // currently holds a sender for the previous out body, it
                 // will get dropped. This terminates the stream.
@@ -152,23 +282,36 @@ impl<S, T, E> Pipeline<S, T>
                 }
             }
             Frame::Body(Some(chunk)) => {
-                trace!("read out body chunk");
+                trace!("read out body chunk; conn={}; seq={}", self.conn, self.read_seq.wrapping_sub(1));
                 try!(self.process_out_body_chunk(chunk));
             }
             Frame::Body(None) => {
-                trace!("read out body EOF");
+                trace!("read out body EOF; conn={}; seq={}", self.conn, self.read_seq.wrapping_sub(1));
                 // Drop the sender.
                 // TODO: Ensure a sender exists
                 let _ = self.out_body.take();
             }
+            Frame::Ping => {
+                trace!("read Frame::Ping; conn={}", self.conn);
+                self.pong_pending = true;
+            }
+            Frame::Pong => {
+                // Reading the frame is all the keepalive needs
+                trace!("read Frame::Pong; conn={}", self.conn);
+            }
+            Frame::GoAway => {
+                // The peer is expected to follow with `Frame::Done`
+                trace!("read Frame::GoAway; conn={}", self.conn);
+            }
             Frame::Done => {
-                trace!("read Frame::Done");
+                trace!("read Frame::Done; conn={}", self.conn);
                 // At this point, we just return. This works
                 // because tick() will be called again and go
                 // through the read-cycle again.
                 self.run = false;
             }
             Frame::Error(_) => {
+                debug!("read Frame::Error, closing connection; conn={}", self.conn);
                 // At this point, the transport is toast, there
                 // isn't much else that we can do. Killing the task
                 // will cause all in-flight requests to abort, but
@@ -181,24 +324,25 @@ impl<S, T, E> Pipeline<S, T>
     }
 
     fn process_out_body_chunk(&mut self, chunk: T::BodyOut) -> io::Result<()> {
-        trace!("process_out_body_chunk");
+        let seq = self.read_seq.wrapping_sub(1);
+
         match self.out_body.take() {
             Some(BodySender::Ready(sender)) => {
-                debug!("sending a chunk");
                 // Try sending the out body chunk
                 let mut busy = sender.send(Ok(chunk));
                 match busy.poll() {
                     Ok(Async::Ready(s)) => {
-                        debug!("immediately done");
+                        trace!("sent out body chunk; conn={}; seq={}", self.conn, seq);
                         self.out_body = Some(BodySender::Ready(s));
                     }
-                    Err(_e) => {} // interest canceled
+                    Err(_e) => {
+                        trace!("out body interest canceled; conn={}; seq={}", self.conn, seq);
+                    }
                     Ok(Async::NotReady) => {
-                        debug!("not done yet");
+                        trace!("out body chunk buffered; conn={}; seq={}", self.conn, seq);
                         self.out_body = Some(BodySender::Busy(busy));
                     }
                 }
-                debug!("wut");
             }
             Some(BodySender::Busy(..)) => {
                 // This case should never happen but it may be better to fail a
@@ -207,7 +351,7 @@ impl<S, T, E> Pipeline<S, T>
                 unimplemented!();
             }
             None => {
-                debug!("interest canceled");
+                trace!("dropping out body chunk, interest canceled; conn={}; seq={}", self.conn, seq);
                 // The rx half canceled interest, there is nothing else to do
             }
         }
@@ -215,15 +359,71 @@ impl<S, T, E> Pipeline<S, T>
         Ok(())
     }
 
+    fn keepalive_activity(&mut self) {
+        if let Some(ref mut keepalive) = self.keepalive {
+            keepalive.activity();
+        }
+    }
+
+    fn write_keepalive_frames(&mut self) -> io::Result<()> {
+        // Don't interrupt a body, the frames are written once it is done
+        if self.pong_pending && self.in_body.is_none() && self.transport.poll_write().is_ready() {
+            trace!("writing Frame::Pong; conn={}", self.conn);
+            try!(self.transport.write(Frame::Pong));
+            self.observer.frame_written();
+            self.pong_pending = false;
+        }
+
+        // Once no more frames are read, the peer's answers would go unnoticed
+        if !self.run && self.keepalive.is_some() {
+            trace!("done reading, disabling keepalive; conn={}", self.conn);
+            self.keepalive = None;
+        }
+
+        let ping = match self.keepalive {
+            Some(ref mut keepalive) => {
+                match keepalive.poll() {
+                    Ok(ping) => ping,
+                    Err(e) => {
+                        debug!("keepalive timed out, closing connection; conn={}", self.conn);
+                        return Err(e);
+                    }
+                }
+            }
+            None => false,
+        };
+
+        if ping && self.in_body.is_none() && self.transport.poll_write().is_ready() {
+            trace!("writing Frame::Ping; conn={}", self.conn);
+            try!(self.transport.write(Frame::Ping));
+            self.observer.frame_written();
+
+            if let Some(ref mut keepalive) = self.keepalive {
+                try!(keepalive.ping_sent());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_goaway(&mut self) -> io::Result<()> {
+        // Don't interrupt a response body
+        if self.goaway_pending && self.in_body.is_none() && self.transport.poll_write().is_ready() {
+            trace!("writing Frame::GoAway; conn={}", self.conn);
+            try!(self.transport.write(Frame::GoAway));
+            self.observer.frame_written();
+            self.goaway_pending = false;
+        }
+
+        Ok(())
+    }
+
     fn write_in_frames(&mut self) -> io::Result<()> {
-        trace!("write_in_frames");
         while self.transport.poll_write().is_ready() {
             // Ensure the current in body is fully written
             if !try!(self.write_in_body()) {
-                debug!("write in body not done");
                 break;
             }
-            debug!("write in body done");
 
             // Write the next in-flight in message
             if let Some(resp) = self.dispatch.poll() {
@@ -237,11 +437,17 @@ impl<S, T, E> Pipeline<S, T>
     }
 
     fn write_in_message(&mut self, message: Result<Message<S::InMsg, S::InBodyStream>, S::Error>) -> io::Result<()> {
-        trace!("write_in_message");
+        let seq = self.write_seq;
+        self.write_seq += 1;
+
         match message {
             Ok(Message::WithoutBody(val)) => {
-                trace!("got in_flight value with body");
-                try!(self.transport.write(Frame::Message(val)));
+                trace!("writing in message; conn={}; seq={}", self.conn, seq);
+                if let Err(e) = self.transport.write(Frame::Message(val)) {
+                    return self.write_in_failed(e);
+                }
+
+                self.observer.frame_written();
 
                 // TODO: don't panic maybe if this isn't true?
                 assert!(self.in_body.is_none());
@@ -250,8 +456,13 @@ impl<S, T, E> Pipeline<S, T>
                 self.in_body = None;
             }
             Ok(Message::WithBody(val, body)) => {
-                trace!("got in_flight value with body");
-                try!(self.transport.write(Frame::Message(val)));
+                trace!("writing in message with body; conn={}; seq={}", self.conn, seq);
+                if let Err(e) = self.transport.write(Frame::Message(val)) {
+                    // The body stream is dropped along with the message
+                    return self.write_in_failed(e);
+                }
+
+                self.observer.frame_written();
 
                 // TODO: don't panic maybe if this isn't true?
                 assert!(self.in_body.is_none());
@@ -260,35 +471,60 @@ impl<S, T, E> Pipeline<S, T>
                 self.in_body = Some(body);
             }
             Err(e) => {
-                trace!("got in_flight error");
+                trace!("writing in error; conn={}; seq={}", self.conn, seq);
                 try!(self.transport.write(Frame::Error(e)));
+                self.observer.frame_written();
             }
         }
 
         Ok(())
     }
 
+    fn write_in_failed(&mut self, err: io::Error) -> io::Result<()> {
+        debug!("failed to write in message; conn={}; seq={}; err={:?}",
+               self.conn, self.write_seq.wrapping_sub(1), err);
+
+        // Only the message failed to be written, the transport is still
+        // usable. Let the dispatch decide what the peer should see instead.
+        if let Some(e) = self.dispatch.write_failed(err) {
+            try!(self.transport.write(Frame::Error(e)));
+            self.observer.frame_written();
+        }
+
+        Ok(())
+    }
+
     // Returns true if the response body is fully written
     fn write_in_body(&mut self) -> io::Result<bool> {
-        trace!("write_in_body");
+        let seq = self.write_seq.wrapping_sub(1);
+
         if let Some(ref mut body) = self.in_body {
             while self.transport.poll_write().is_ready() {
                 match body.poll() {
                     Ok(Async::Ready(Some(chunk))) => {
+                        // The message head has already been written, so a
+                        // failure here cannot be isolated to this message.
+                        trace!("writing in body chunk; conn={}; seq={}", self.conn, seq);
                         let r = try!(self.transport.write(Frame::Body(Some(chunk))));
+
+                        self.observer.frame_written();
+                        self.observer.body_chunk_sent();
+
                         if !r.is_ready() {
                             return Ok(false);
                         }
                     }
                     Ok(Async::Ready(None)) => {
+                        trace!("writing in body EOF; conn={}; seq={}", self.conn, seq);
                         try!(self.transport.write(Frame::Body(None)));
+                        self.observer.frame_written();
                         // Response body flushed, let fall through
                     }
                     Err(_) => {
                         unimplemented!();
                     }
                     Ok(Async::NotReady) => {
-                        debug!("not ready");
+                        trace!("in body stream not ready; conn={}; seq={}", self.conn, seq);
                         return Ok(false);
                     }
                 }
@@ -301,6 +537,12 @@ impl<S, T, E> Pipeline<S, T>
 
     fn flush(&mut self) -> io::Result<()> {
         self.is_flushed = try!(self.transport.flush()).is_ready();
+
+        if !self.is_flushed {
+            trace!("transport not flushed; conn={}", self.conn);
+            self.observer.flush_blocked();
+        }
+
         Ok(())
     }
 }
@@ -315,17 +557,40 @@ impl<S, T, E> Future for Pipeline<S, T>
 
     // Tick the pipeline state machine
     fn poll(&mut self) -> Poll<(), io::Error> {
-        trace!("Pipeline::tick");
+        trace!("Pipeline::tick; conn={}", self.conn);
 
         // Always flush the transport first
         try!(self.flush());
 
+        // Check if the connection was told to drain
+        self.poll_drain();
+
         // First read off data from the socket
-        try!(self.read_out_frames());
+        let mut at_capacity = try!(self.read_out_frames());
+
+        // Answer pings, and ping the peer if the connection is idle. In-flight
+        // requests are dropped along with the connection if the peer does not
+        // answer.
+        try!(self.write_keepalive_frames());
+
+        // Tell the peer to migrate as soon as draining starts
+        try!(self.write_goaway());
 
         // Handle completed responses
         try!(self.write_in_frames());
 
+        // Completing a response body may have unblocked the keepalive and
+        // goaway frames
+        try!(self.write_keepalive_frames());
+        try!(self.write_goaway());
+
+        // Writing responses could un-block the dispatch, in which case read
+        // the requests that were held back.
+        while at_capacity && self.dispatch.is_ready() {
+            at_capacity = try!(self.read_out_frames());
+            try!(self.write_in_frames());
+        }
+
         // Try flushing buffered writes
         try!(self.flush());
 
@@ -339,6 +604,9 @@ impl<S, T, E> Future for Pipeline<S, T>
         //
         // 3. There are no further responses to write to the transport.
         //
+        // When draining, the first condition is met once no more requests are
+        // read, and the goaway frame has to be written as well.
+        //
         // It is necessary to perfom these three checks in order to handle the
         // case where the client shuts down half the socket.
         //
@@ -350,3 +618,13 @@ impl<S, T, E> Future for Pipeline<S, T>
         Ok(Async::NotReady)
     }
 }
+
+impl<S, T> Drop for Pipeline<S, T>
+    where T: Transport,
+          S: Dispatch,
+{
+    fn drop(&mut self) {
+        trace!("connection closed; conn={}", self.conn);
+        self.observer.connection_closed();
+    }
+}
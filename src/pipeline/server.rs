@@ -1,7 +1,15 @@
 use super::{pipeline, Error, Message, ServerService, Transport};
+use context::RequestContext;
+use in_flight::InFlightSet;
+use registry::{self, Registration, Registry};
+use server::{ConnectionState, Drain};
+use metrics::Observer;
 use std::collections::VecDeque;
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use futures::{Future, Poll, Async};
+use tokio_core::reactor::Handle;
 
 // TODO:
 //
@@ -15,12 +23,28 @@ pub struct Server<S, T>
           T: Transport,
 {
     inner: pipeline::Pipeline<Dispatch<S>, T>,
+    // Entry in the registry the connection was added to, see `set_registry`
+    registration: Option<Registration>,
 }
 
 struct Dispatch<S: ServerService> {
     // The service handling the connection
     service: S,
-    in_flight: VecDeque<InFlight<S::Future>>,
+    // Responses, along with the context of, and the time each request was
+    // dispatched
+    in_flight: InFlightSet<(RequestContext, Instant, InFlight<S::Future>)>,
+    // Slots of the in-flight requests, in the order they were received
+    order: VecDeque<usize>,
+    // Notified of request events
+    observer: Arc<Observer>,
+    // Identifies the connection in request contexts
+    conn: usize,
+    // Sequence number of the next request
+    next_seq: u64,
+    // Extracts the trace context from requests
+    trace_extractor: Option<Box<Fn(&S::Request) -> Option<String> + Send>>,
+    // Requests are no longer read once this many are in flight
+    max_in_flight: usize,
 }
 
 enum InFlight<F: Future> {
@@ -28,6 +52,9 @@ enum InFlight<F: Future> {
     Done(Result<F::Item, F::Error>),
 }
 
+/// The default number of requests that can be in flight at once.
+const MAX_IN_FLIGHT_REQUESTS: usize = 32;
+
 impl<T, S, E> Server<S, T>
     where T: Transport<Error = E>,
           S: ServerService<Request = T::Out, Response = T::In, Body = T::BodyIn, Error = E>,
@@ -38,19 +65,117 @@ impl<T, S, E> Server<S, T>
     pub fn new(service: S, transport: T) -> io::Result<Server<S, T>> {
         let dispatch = Dispatch {
             service: service,
-            in_flight: VecDeque::with_capacity(32),
+            in_flight: InFlightSet::new(),
+            order: VecDeque::with_capacity(32),
+            observer: Arc::new(()),
+            conn: 0,
+            next_seq: 0,
+            trace_extractor: None,
+            max_in_flight: MAX_IN_FLIGHT_REQUESTS,
         };
 
         // Create the pipeline dispatcher
-        let pipeline = try!(pipeline::Pipeline::new(dispatch, transport));
+        let mut pipeline = try!(pipeline::Pipeline::new(dispatch, transport));
+
+        // Requests are identified by the connection id assigned by the
+        // pipeline
+        let conn = pipeline.connection_id();
+        pipeline.dispatch_mut().conn = conn;
 
         // Return the server task
-        Ok(Server { inner: pipeline })
+        Ok(Server {
+            inner: pipeline,
+            registration: None,
+        })
+    }
+
+    /// Returns a snapshot of the state of the connection
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state()
+    }
+
+    /// Returns a handle used to drain the connection
+    ///
+    /// See `Drain` for more details.
+    pub fn drain_handle(&self) -> Drain {
+        self.inner.drain_handle()
+    }
+
+    /// Adds the connection to `registry`, removing it from any registry it
+    /// was previously added to
+    ///
+    /// The connection is listed by the registry until the server is dropped.
+    /// See `Registry` for more details.
+    pub fn set_registry(&mut self, registry: &Registry) {
+        let state = self.inner.state();
+        let drain = self.inner.drain_handle();
+
+        // Drop the previous registration first, removing its entry
+        self.registration = None;
+        self.registration = Some(registry::register(registry, state, drain));
+    }
+
+    /// Sets the observer notified of events on this connection
+    ///
+    /// See the `metrics` module for more details.
+    pub fn set_observer(&mut self, observer: Arc<Observer>) {
+        self.inner.dispatch_mut().observer = observer.clone();
+        self.inner.set_observer(observer);
+    }
+
+    /// Sets the max number of requests dispatched to the service at once
+    ///
+    /// Once this many requests are waiting to be responded to, no more
+    /// requests are read from the transport until a response is written. The
+    /// default is 32.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn set_max_in_flight(&mut self, max: usize) {
+        assert!(max > 0, "max in flight requests must be greater than 0");
+        self.inner.dispatch_mut().max_in_flight = max;
+    }
+
+    /// Enables keepalive pings on the connection
+    ///
+    /// Once no frame was read from the transport for `interval`, a
+    /// `Frame::Ping` is written. If no frame, usually the peer's `Frame::Pong`,
+    /// is read within `timeout`, the connection is closed, dropping the
+    /// in-flight requests. The `Transport` must be able to encode both frames.
+    ///
+    /// While reading is held back because `max_in_flight` requests are in
+    /// flight, the connection is not considered idle.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration, handle: &Handle) {
+        self.inner.set_keepalive(interval, timeout, handle);
+    }
+
+    /// Sets whether a `Frame::GoAway` is written when the connection starts
+    /// draining
+    ///
+    /// The frame tells the peer to migrate to another connection. It is not
+    /// written by default, enabling it requires the `Transport` to be able to
+    /// encode the frame. See `Drain` for more details.
+    pub fn set_goaway(&mut self, enabled: bool) {
+        self.inner.set_goaway(enabled);
+    }
+
+    /// Sets the function used to extract a trace context from requests
+    ///
+    /// The trace context, such as a trace id sent by the peer in a request
+    /// header, is included in the `RequestContext` of the request, which is
+    /// available from `request_context` while the service processes the
+    /// request.
+    pub fn set_trace_extractor<F>(&mut self, extract: F)
+        where F: Fn(&S::Request) -> Option<String> + Send + 'static,
+    {
+        self.inner.dispatch_mut().trace_extractor = Some(Box::new(extract));
     }
 }
 
 impl<S> pipeline::Dispatch for Dispatch<S>
     where S: ServerService,
+          S::Error: From<Error<S::Error>>,
 {
     type InMsg = S::Response;
     type InBody = S::Body;
@@ -59,27 +184,66 @@ impl<S> pipeline::Dispatch for Dispatch<S>
     type Error = S::Error;
 
     fn dispatch(&mut self, request: Self::OutMsg) -> io::Result<()> {
-        let response = self.service.call(request);
-        self.in_flight.push_back(InFlight::Active(response));
+        let trace_id = self.trace_extractor.as_ref().and_then(|extract| extract(&request));
+        let seq = self.next_seq;
+        let cx = RequestContext::new(self.conn, seq, trace_id);
+
+        self.next_seq += 1;
+
+        trace!("dispatching request; conn={}; seq={}; trace_id={:?}",
+               cx.connection_id(), cx.request_seq(), cx.trace_id());
+
+        let service = &mut self.service;
+        let response = cx.enter(|| service.call(request));
+
+        let slot = self.in_flight.insert((cx, Instant::now(), InFlight::Active(response)));
+        self.order.push_back(slot);
+
+        self.observer.request_dispatched(self.in_flight.len());
         Ok(())
     }
 
     fn poll(&mut self) -> Option<Result<Message<Self::InMsg, Self::InBodyStream>, Self::Error>> {
-        for slot in self.in_flight.iter_mut() {
-            slot.poll();
-        }
-        match self.in_flight.front() {
-            Some(&InFlight::Done(_)) => {}
+        // Only poll the responses that were notified
+        self.in_flight.poll_ready(|_, &mut (ref cx, _, ref mut response)| {
+            cx.enter(|| response.poll());
+        });
+
+        // Responses are written in the order the requests were received
+        let slot = match self.order.front() {
+            Some(&slot) => slot,
+            None => return None,
+        };
+
+        match self.in_flight.get(slot) {
+            Some(&(_, _, InFlight::Done(_))) => {}
             _ => return None,
         }
-        match self.in_flight.pop_front() {
-            Some(InFlight::Done(res)) => Some(res),
+
+        self.order.pop_front();
+
+        match self.in_flight.remove(slot) {
+            (cx, started, InFlight::Done(res)) => {
+                trace!("response complete; conn={}; seq={}", cx.connection_id(), cx.request_seq());
+                self.observer.response_completed(started.elapsed());
+                Some(res)
+            }
             _ => panic!(),
         }
     }
 
-    fn has_in_flight(&self) -> bool {
-        !self.in_flight.is_empty()
+    fn write_failed(&mut self, err: io::Error) -> Option<Self::Error> {
+        // The peer is waiting for a response to the request, respond with an
+        // error instead.
+        Some(Error::Io(err).into())
+    }
+
+    fn is_ready(&self) -> bool {
+        self.in_flight.len() < self.max_in_flight
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.len()
     }
 }
 
@@ -108,6 +272,12 @@ impl<T, S, E> Future for Server<S, T>
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<(), io::Error> {
-        self.inner.poll()
+        let ret = self.inner.poll();
+
+        if let Some(ref mut registration) = self.registration {
+            registration.update(self.inner.state());
+        }
+
+        ret
     }
 }
@@ -0,0 +1,112 @@
+use drain::Drain;
+use server::ConnectionState;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Tracks the state of live pipeline and multiplex connections, so that they
+/// can be listed and drained.
+///
+/// Connections are added by passing the registry to the `set_registry`
+/// function of `pipeline::Server` or `multiplex::Server`, and are removed once
+/// the server is dropped. This may happen at any point, such as once a TLS
+/// handshake completed.
+///
+/// ```rust,ignore
+/// let registry = Registry::new();
+/// let registry2 = registry.clone();
+///
+/// let srv = server::listen(&handle, addr, move |socket| {
+///     let mut server = try!(pipeline::Server::new(service, framed(socket)));
+///     server.set_registry(&registry2);
+///     Ok(server)
+/// });
+///
+/// for connection in registry.connections() {
+///     println!("{:?}", connection);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Registry {
+    connections: Arc<Mutex<HashMap<usize, (ConnectionState, Drain)>>>,
+}
+
+/// The entry of a connection in a `Registry`, removed when dropped.
+pub struct Registration {
+    registry: Registry,
+    // The state last written to the registry
+    state: ConnectionState,
+}
+
+impl Registry {
+    /// Create a new, empty, `Registry`
+    pub fn new() -> Registry {
+        Registry {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the state of all registered connections, ordered by connection
+    /// id.
+    ///
+    /// The state of each connection is updated when its task is polled.
+    pub fn connections(&self) -> Vec<ConnectionState> {
+        let connections = self.connections.lock().unwrap();
+        let mut ret: Vec<_> = connections.values().map(|entry| entry.0.clone()).collect();
+
+        ret.sort_by_key(|state| state.connection_id);
+        ret
+    }
+
+    /// Drains the registered connection with the given id. Returns false if
+    /// there is no such connection.
+    ///
+    /// See `Drain` for more details.
+    pub fn drain_connection(&self, connection_id: usize) -> bool {
+        let connections = self.connections.lock().unwrap();
+
+        match connections.get(&connection_id) {
+            Some(entry) => {
+                entry.1.drain();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Adds a connection to `registry`
+pub fn register(registry: &Registry, state: ConnectionState, drain: Drain) -> Registration {
+    registry.connections.lock().unwrap().insert(state.connection_id, (state.clone(), drain));
+
+    Registration {
+        registry: registry.clone(),
+        state: state,
+    }
+}
+
+impl Registration {
+    /// Replace the state of the registered connection, if it changed
+    pub fn update(&mut self, state: ConnectionState) {
+        // Most polls don't change the state, avoid contending on the lock
+        if state == self.state {
+            return;
+        }
+
+        let mut connections = self.registry.connections.lock().unwrap();
+
+        if let Some(entry) = connections.get_mut(&state.connection_id) {
+            entry.0 = state.clone();
+        }
+
+        self.state = state;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // Don't panic again if the lock was poisoned by a panicking connection
+        if let Ok(mut connections) = self.registry.connections.lock() {
+            connections.remove(&self.state.connection_id);
+        }
+    }
+}
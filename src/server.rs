@@ -1,19 +1,53 @@
 //! A generic Tokio TCP server implementation.
 
-use std::io;
+use std::{cmp, io, mem};
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use futures::stream::Stream;
-use futures::Future;
+use futures::{Async, Future, Poll};
+use io::{Peekable, TryRead};
+
+pub use drain::Drain;
+pub use registry::Registry;
 use take::Take;
 use tokio_core::reactor::Handle;
 use tokio_core::net::{TcpListener, TcpStream};
 
 /// A handle to a running server.
+///
+/// Connections are not tracked by the server, as they are only known to be
+/// pipeline or multiplex connections once handed to a `Server`. Use a
+/// `Registry` to list and drain them.
 pub struct ServerHandle {
     local_addr: SocketAddr,
 }
 
+/// A snapshot of the state of a connection, used to debug stuck connections.
+///
+/// Returned by `Registry::connections`, and by the `state` functions of
+/// `pipeline::Server` and `multiplex::Server`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionState {
+    /// Identifies the connection in log events, see `RequestContext`
+    pub connection_id: usize,
+    /// Number of requests dispatched to the service that have not been
+    /// responded to yet
+    pub in_flight: usize,
+    /// True while a response body is being written to the transport
+    pub in_body: bool,
+    /// True while the sender of the current request body is waiting for the
+    /// service to consume the chunks already sent
+    pub out_body: bool,
+    /// Number of requests read from the transport that are waiting for the
+    /// service to be ready
+    pub dispatch_deque: usize,
+    /// True when all data written to the transport has been flushed
+    pub is_flushed: bool,
+    /// True once the connection started draining, see `Drain`
+    pub draining: bool,
+}
+
 /// Create a new `Task` to handle a server socket.
 pub trait NewTask: Send + 'static {
     /// The `Task` value created by this factory
@@ -79,7 +113,9 @@ pub fn listen<T>(handle: &Handle,
         error!("server error: {}", e);
     }));
 
-    Ok(ServerHandle { local_addr: addr })
+    Ok(ServerHandle {
+        local_addr: addr,
+    })
 }
 
 impl ServerHandle {
@@ -110,3 +146,192 @@ impl<T, U> NewTask for Take<T>
         self.take()(stream)
     }
 }
+
+/*
+ *
+ * ===== Sniff =====
+ *
+ */
+
+/// A `NewTask` serving several protocols on one listener.
+///
+/// The first bytes of each connection are compared against the magic bytes
+/// registered for each protocol, in the order the protocols were added, and
+/// the connection is handed to the first protocol that matches. Bytes read
+/// while sniffing are replayed by the `Peekable` passed to the protocol's task
+/// factory, so the selected transport sees the connection from the start.
+///
+/// The socket is read from rather than peeked at, even though `TcpStream`
+/// implements `Peek`: a peek returns the same bytes until more arrive, so
+/// waiting for the rest of the magic bytes with peeks would spin instead of
+/// waiting on the reactor.
+///
+/// ```rust,ignore
+/// let sniff = Sniff::new()
+///     .protocol(b"RPC1", |socket| rpc::serve(socket))
+///     .fallback(|socket| admin::serve(socket));
+///
+/// server::listen(&handle, addr, sniff)
+/// ```
+pub struct Sniff {
+    inner: Arc<SniffInner>,
+}
+
+struct SniffInner {
+    protocols: Vec<(Vec<u8>, BoxNewTask)>,
+    // Used when no magic bytes match
+    fallback: Option<BoxNewTask>,
+    // Length of the longest magic bytes
+    max_len: usize,
+}
+
+type BoxTask = Box<Future<Item=(), Error=io::Error>>;
+
+type BoxNewTask = Box<Fn(Peekable<TcpStream>) -> io::Result<BoxTask> + Send + Sync>;
+
+// Reads the first bytes of a connection until a protocol is selected
+struct Sniffing {
+    socket: Option<TcpStream>,
+    // Bytes read so far
+    buf: Vec<u8>,
+    sniff: Arc<SniffInner>,
+}
+
+enum Selected {
+    Protocol(usize),
+    Fallback,
+    NeedMore,
+}
+
+impl Sniff {
+    /// Create a new `Sniff` without any protocols
+    pub fn new() -> Sniff {
+        Sniff {
+            inner: Arc::new(SniffInner {
+                protocols: vec![],
+                fallback: None,
+                max_len: 0,
+            }),
+        }
+    }
+
+    /// Serve connections starting with `magic` using tasks created by
+    /// `new_task`.
+    pub fn protocol<F, U>(mut self, magic: &[u8], new_task: F) -> Sniff
+        where F: Fn(Peekable<TcpStream>) -> io::Result<U> + Send + Sync + 'static,
+              U: Future<Item=(), Error=io::Error> + 'static,
+    {
+        {
+            let inner = self.inner_mut();
+            inner.max_len = cmp::max(inner.max_len, magic.len());
+            inner.protocols.push((magic.to_vec(), box_new_task(new_task)));
+        }
+
+        self
+    }
+
+    /// Serve connections not matching any protocol using tasks created by
+    /// `new_task`.
+    ///
+    /// Without a fallback, such connections are closed.
+    pub fn fallback<F, U>(mut self, new_task: F) -> Sniff
+        where F: Fn(Peekable<TcpStream>) -> io::Result<U> + Send + Sync + 'static,
+              U: Future<Item=(), Error=io::Error> + 'static,
+    {
+        self.inner_mut().fallback = Some(box_new_task(new_task));
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut SniffInner {
+        // Only shared with connections once the listener is running
+        Arc::get_mut(&mut self.inner).unwrap()
+    }
+}
+
+impl NewTask for Sniff {
+    type Item = Box<Future<Item=(), Error=io::Error>>;
+
+    fn new_task(&self, stream: TcpStream) -> io::Result<Self::Item> {
+        let sniffing = Sniffing {
+            socket: Some(stream),
+            buf: Vec::with_capacity(self.inner.max_len),
+            sniff: self.inner.clone(),
+        };
+
+        Ok(Box::new(sniffing.flatten()))
+    }
+}
+
+impl Future for Sniffing {
+    type Item = BoxTask;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<BoxTask, io::Error> {
+        let mut eof = false;
+
+        loop {
+            let new_task = match self.sniff.select(&self.buf, eof) {
+                Selected::Protocol(i) => &self.sniff.protocols[i].1,
+                Selected::Fallback => {
+                    match self.sniff.fallback {
+                        Some(ref new_task) => new_task,
+                        None => {
+                            debug!("closing connection with unrecognized protocol");
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      "unrecognized protocol"));
+                        }
+                    }
+                }
+                Selected::NeedMore => {
+                    let socket = self.socket.as_mut().unwrap();
+                    let mut chunk = [0; 64];
+                    let n = cmp::min(chunk.len(), self.sniff.max_len - self.buf.len());
+
+                    match try!(socket.try_read(&mut chunk[..n])) {
+                        Async::Ready(0) => eof = true,
+                        Async::Ready(n) => self.buf.extend_from_slice(&chunk[..n]),
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+
+                    continue;
+                }
+            };
+
+            trace!("protocol selected; sniffed={:?}", self.buf);
+
+            // Hand off the bytes read so far along with the socket
+            let socket = self.socket.take().unwrap();
+            let buf = mem::replace(&mut self.buf, vec![]);
+            let socket = Peekable::with_prefix(socket, buf);
+
+            return new_task(socket).map(Async::Ready);
+        }
+    }
+}
+
+impl SniffInner {
+    fn select(&self, buf: &[u8], eof: bool) -> Selected {
+        for (i, &(ref magic, _)) in self.protocols.iter().enumerate() {
+            if buf.starts_with(magic) {
+                return Selected::Protocol(i);
+            }
+
+            // Wait for more bytes before trying protocols added later
+            if !eof && magic.starts_with(buf) {
+                return Selected::NeedMore;
+            }
+        }
+
+        Selected::Fallback
+    }
+}
+
+fn box_new_task<F, U>(new_task: F) -> BoxNewTask
+    where F: Fn(Peekable<TcpStream>) -> io::Result<U> + Send + Sync + 'static,
+          U: Future<Item=(), Error=io::Error> + 'static,
+{
+    Box::new(move |socket| {
+        let task = try!(new_task(socket));
+        Ok(Box::new(task) as BoxTask)
+    })
+}
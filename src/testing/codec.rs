@@ -0,0 +1,194 @@
+//! Conformance checks for `Parse` and `Serialize` implementations.
+//!
+//! A parser may be handed a frame in any number of pieces, depending on how
+//! the bytes arrived from the socket, and split across the blocks of the read
+//! buffer. `check` serializes sample messages and feeds the resulting bytes to
+//! the parser in different ways, panicking if any of them parse differently.
+
+use {Parse, ParseBuf, Serialize};
+use bytes::{BlockBuf, MutBuf};
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+use std::fmt;
+
+/// Size of the read buffer blocks, small enough for frames to span blocks
+const BLOCK_SIZE: usize = 8;
+
+/// Number of times the input is fed to the parser in random pieces
+const RANDOM_SPLITS: usize = 32;
+
+/// Check that `parse` parses back the messages serialized by `serialize`.
+///
+/// The messages are serialized into a single buffer, which is then fed to the
+/// parser:
+///
+/// * all at once,
+/// * one byte at a time,
+/// * in randomly sized pieces, a number of times.
+///
+/// Each time, `parse` and, once all of the input has been fed, `done` must
+/// return all of the messages, in order, and consume all of the input. The
+/// last message may be returned by `done`, for protocols in which it is
+/// delimited by EOF. When the input is cut short in the middle of the last
+/// message, `done` must not return a message other than that one.
+///
+/// The random pieces are generated from a random seed, which is included in
+/// the failure message. Pass it to `check_with_seed` to reproduce a failure.
+///
+/// # Panics
+///
+/// Panics, describing the failure, if any of the checks fail. This function
+/// is intended to be called from tests.
+pub fn check<P, S, T>(parse: &mut P, serialize: &mut S, msgs: &[T])
+    where P: Parse<Out = T>,
+          S: Serialize<In = T>,
+          T: Clone + PartialEq + fmt::Debug,
+{
+    check_with_seed(parse, serialize, msgs, rand::random())
+}
+
+/// Like `check`, splitting the input into random pieces generated from
+/// `seed`.
+pub fn check_with_seed<P, S, T>(parse: &mut P, serialize: &mut S, msgs: &[T], seed: u32)
+    where P: Parse<Out = T>,
+          S: Serialize<In = T>,
+          T: Clone + PartialEq + fmt::Debug,
+{
+    let input = serialize_all(serialize, msgs);
+
+    // All at once
+    assert_parses(parse, &input, &[input.len()], msgs, seed);
+
+    // One byte at a time
+    let chunks: Vec<usize> = input.iter().map(|_| 1).collect();
+    assert_parses(parse, &input, &chunks, msgs, seed);
+
+    // Random pieces. The constants keep the seed from being all zeros, which
+    // `XorShiftRng` does not accept.
+    let mut rng = XorShiftRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05]);
+
+    for _ in 0..RANDOM_SPLITS {
+        let mut chunks = vec![];
+        let mut rem = input.len();
+
+        while rem > 0 {
+            let n = rng.gen_range(1, rem + 1);
+            chunks.push(n);
+            rem -= n;
+        }
+
+        assert_parses(parse, &input, &chunks, msgs, seed);
+    }
+
+    // Cut short in the middle of the last message
+    if let Some(last) = msgs.last() {
+        let len = serialize_all(serialize, &[last.clone()]).len();
+
+        if len > 1 {
+            assert_truncated(parse, &input[..input.len() - 1], &msgs[..msgs.len() - 1], last);
+        }
+    }
+}
+
+// Serializes all messages, returning the bytes written
+fn serialize_all<S, T>(serialize: &mut S, msgs: &[T]) -> Vec<u8>
+    where S: Serialize<In = T>,
+          T: Clone + fmt::Debug,
+{
+    let mut input = vec![];
+
+    for msg in msgs {
+        let mut buf = BlockBuf::default();
+
+        if let Err(e) = serialize.serialize(msg.clone(), &mut buf) {
+            panic!("failed to serialize message; msg={:?}; err={:?}", msg, e);
+        }
+
+        let mut bytes = vec![0; buf.len()];
+        assert!(buf.peek_slice(&mut bytes));
+        input.extend_from_slice(&bytes);
+    }
+
+    input
+}
+
+// Feeds `input` to the parser in pieces of the given sizes, then signals EOF
+fn assert_parses<P, T>(parse: &mut P, input: &[u8], chunks: &[usize], expect: &[T], seed: u32)
+    where P: Parse<Out = T>,
+          T: PartialEq + fmt::Debug,
+{
+    let mut buf = read_buf(input.len());
+    let mut parsed = vec![];
+    let mut pos = 0;
+
+    for &n in chunks {
+        buf.write_slice(&input[pos..pos + n]);
+        pos += n;
+
+        loop {
+            match parse.parse(&mut buf) {
+                Ok(Some(msg)) => parsed.push(msg),
+                Ok(None) => break,
+                Err(e) => {
+                    panic!("failed to parse; seed={}; chunks={:?}; offset={}; err={:?}",
+                           seed, chunks, pos, e);
+                }
+            }
+        }
+    }
+
+    // The last message may be delimited by EOF
+    match parse.done(&mut buf) {
+        Ok(Some(msg)) => parsed.push(msg),
+        Ok(None) => {}
+        Err(e) => panic!("`done` failed on EOF; seed={}; chunks={:?}; err={:?}", seed, chunks, e),
+    }
+
+    if parsed != expect {
+        panic!("parsed messages differ; seed={}; chunks={:?}; expected={:?}; actual={:?}",
+               seed, chunks, expect, parsed);
+    }
+
+    if !buf.is_empty() {
+        panic!("input not fully consumed; seed={}; chunks={:?}; remaining={}",
+               seed, chunks, buf.len());
+    }
+}
+
+// Feeds input truncated in the middle of `last` to the parser, then signals
+// EOF
+fn assert_truncated<P, T>(parse: &mut P, input: &[u8], expect: &[T], last: &T)
+    where P: Parse<Out = T>,
+          T: PartialEq + fmt::Debug,
+{
+    let mut buf = read_buf(input.len());
+    let mut parsed = vec![];
+
+    buf.write_slice(input);
+
+    loop {
+        match parse.parse(&mut buf) {
+            Ok(Some(msg)) => parsed.push(msg),
+            Ok(None) => break,
+            Err(e) => panic!("failed to parse truncated input; err={:?}", e),
+        }
+    }
+
+    if parsed != expect {
+        panic!("parsed messages differ on truncated input; expected={:?}; actual={:?}",
+               expect, parsed);
+    }
+
+    // An error, no message, or the message that was cut short, if the bytes
+    // that are left out are not needed to parse it, are all acceptable
+    if let Ok(Some(msg)) = parse.done(&mut buf) {
+        if msg != *last {
+            panic!("`done` returned a different message from truncated input; expected={:?}; actual={:?}",
+                   last, msg);
+        }
+    }
+}
+
+// Creates a read buffer able to hold `len` bytes in small blocks
+fn read_buf(len: usize) -> BlockBuf {
+    BlockBuf::new(len / BLOCK_SIZE + 2, BLOCK_SIZE)
+}
@@ -1,3 +1,13 @@
+//! A scripted `FramedIo` transport.
+//!
+//! `transport` returns a `NewTransport`, to be handed to the code under test,
+//! and a `TransportHandle` used to drive the transport from the test thread.
+//! The handle sends frames to be read by the transport, and controls when the
+//! transport may write or flush, asserting the frames that were written.
+//!
+//! Frames are passed through as is, so `In` and `Out` are usually the
+//! `pipeline::Frame` or `multiplex::Frame` types used by the protocol.
+
 extern crate futures;
 extern crate lazycell;
 extern crate mio;
@@ -10,19 +20,24 @@ use tokio_core::reactor::{PollEvented, Handle};
 use std::{fmt, io};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+use std::time::Duration;
 
+/// A mock transport, scripted by a `TransportHandle`.
 pub struct Transport<In, Out> {
     tx: Sender<Write<In>>,
     source: PollEvented<Io<Out>>,
     pending: Option<In>,
 }
 
+/// Creates the mock `Transport` once it is on the reactor thread.
 pub struct NewTransport<In, Out> {
     tx: Sender<Write<In>>,
     inner: Arc<Mutex<Inner<Out>>>,
     handle: Handle,
 }
 
+/// Drives a mock `Transport` from the test thread.
 pub struct TransportHandle<In, Out> {
     // A Receiver is needed in order to block waiting for messages
     rx: Receiver<Write<In>>,
@@ -119,6 +134,8 @@ impl<In: fmt::Debug, Out> TransportHandle<In, Out> {
         self.inner.lock().unwrap().allow_write(WriteCap::Flush);
     }
 
+    /// Wait for the transport to flush, panicking if anything else happens
+    /// first.
     pub fn assert_flush(&self) {
         match self.rx.recv().unwrap() {
             Write::Flush => {},
@@ -127,11 +144,14 @@ impl<In: fmt::Debug, Out> TransportHandle<In, Out> {
         }
     }
 
+    /// Allow the transport to flush, then wait for it to do so.
     pub fn allow_and_assert_flush(&self) {
         self.allow_flush();
         self.assert_flush();
     }
 
+    /// Wait for the transport to be dropped, panicking if anything else
+    /// happens first.
     pub fn assert_drop(&self) {
         match self.rx.recv().unwrap() {
             Write::Drop => {},
@@ -140,15 +160,18 @@ impl<In: fmt::Debug, Out> TransportHandle<In, Out> {
         }
     }
 
+    /// Allow the transport to write, then wait for it to be dropped.
     pub fn allow_and_assert_drop(&self) {
         self.allow_write();
         self.assert_drop();
     }
 
+    /// Assert that the transport does not write, flush or drop within `ms`
+    /// milliseconds.
     pub fn assert_no_write(&self, ms: u64) {
         // Unfortunately, mpsc::channel() does not support timeouts on recv, so
         // for now just sleep
-        super::sleep_ms(ms);
+        thread::sleep(Duration::from_millis(ms));
 
         if let Ok(v) = self.rx.try_recv() {
             panic!("expected no write; received={:?}", v);
@@ -244,6 +267,7 @@ impl<In, Out> NewTransport<In, Out>
     where In: Send + 'static,
           Out: Send + 'static,
 {
+    /// Create the mock transport, registering it with the reactor.
     pub fn new_transport(self) -> io::Result<Transport<In, Out>> {
         let NewTransport { tx, inner, handle } = self;
 
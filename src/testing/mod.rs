@@ -0,0 +1,24 @@
+//! Utilities for testing protocol implementations
+//!
+//! Only available with the `testing` cargo feature enabled.
+//!
+//! `mock::transport` provides a transport that is scripted frame by frame,
+//! which allows testing a `pipeline::Server` or `multiplex::Server`, and the
+//! service it dispatches to, without any I/O:
+//!
+//! ```rust,ignore
+//! let (mock, new_transport) = mock::transport(handle.clone());
+//!
+//! let server = pipeline::Server::new(service, new_transport.new_transport()?)?;
+//! handle.spawn(server.map_err(|_| ()));
+//!
+//! mock.allow_write();
+//! mock.send(Frame::Message("hello"));
+//! assert_eq!("hello", mock.next_write().unwrap_msg());
+//! ```
+//!
+//! `codec::check` verifies that a `Parse` implementation handles frames no
+//! matter how the bytes are split up when they arrive.
+
+pub mod codec;
+pub mod mock;
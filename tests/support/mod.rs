@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+// Shared with the `testing` feature of the crate
+#[path = "../../src/testing/mock.rs"]
 pub mod mock;
 
 use std::time::Duration;
@@ -0,0 +1,128 @@
+#![cfg(feature = "testing")]
+
+extern crate bytes;
+extern crate tokio_proto;
+
+use bytes::{BlockBuf, MutBuf};
+use tokio_proto::{Parse, ParseBuf, Serialize};
+use tokio_proto::testing::codec;
+use std::io;
+
+#[test]
+fn test_conforming_codec() {
+    let msgs = vec!["hello".to_string(), "".to_string(), "a longer line of text".to_string()];
+    codec::check(&mut LineParser, &mut LineSerializer, &msgs);
+}
+
+#[test]
+#[should_panic(expected = "parsed messages differ")]
+fn test_parser_expecting_whole_reads() {
+    let msgs = vec!["hello".to_string(), "world".to_string()];
+    codec::check(&mut WholeReadParser, &mut LineSerializer, &msgs);
+}
+
+#[test]
+fn test_last_line_delimited_by_eof() {
+    let msgs = vec!["hello".to_string(), "world".to_string()];
+    codec::check(&mut EofLineParser, &mut LineSerializer, &msgs);
+}
+
+#[test]
+fn test_reproducing_random_splits() {
+    let msgs = vec!["hello".to_string(), "world".to_string()];
+    codec::check_with_seed(&mut LineParser, &mut LineSerializer, &msgs, 1234);
+}
+
+#[test]
+#[should_panic(expected = "seed=1234")]
+fn test_failure_reports_seed() {
+    let msgs = vec!["hello".to_string(), "world".to_string()];
+    codec::check_with_seed(&mut WholeReadParser, &mut LineSerializer, &msgs, 1234);
+}
+
+/// Parses `\n` terminated lines
+struct LineParser;
+
+impl Parse for LineParser {
+    type Out = String;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        let n = match buf.find_byte(b'\n') {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let mut line = vec![0; n];
+        buf.peek_slice(&mut line);
+        buf.drop(n + 1);
+
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8"))
+    }
+
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-line"))
+        }
+    }
+}
+
+/// Parses `\n` terminated lines, the last of which may be terminated by EOF
+struct EofLineParser;
+
+impl Parse for EofLineParser {
+    type Out = String;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        LineParser.parse(buf)
+    }
+
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let mut line = vec![0; buf.len()];
+        buf.peek_slice(&mut line);
+        buf.drop(line.len());
+
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8"))
+    }
+}
+
+/// Assumes each read ends on a line boundary
+struct WholeReadParser;
+
+impl Parse for WholeReadParser {
+    type Out = String;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        let mut bytes = vec![0; buf.len()];
+        buf.peek_slice(&mut bytes);
+
+        if bytes.pop() != Some(b'\n') {
+            return Ok(None);
+        }
+
+        buf.drop(bytes.len() + 1);
+        Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+}
+
+/// Serializes lines, appending a `\n`
+struct LineSerializer;
+
+impl Serialize for LineSerializer {
+    type In = String;
+
+    fn serialize(&mut self, msg: String, buf: &mut BlockBuf) -> io::Result<()> {
+        buf.write_slice(msg.as_bytes());
+        buf.write_slice(b"\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+extern crate bytes;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use bytes::{BlockBuf, MutBuf};
+use futures::{oneshot, Future};
+use futures::stream::Empty;
+use tokio_core::reactor::Core;
+use tokio_proto::{duplex, pipeline, Codec, CodecFramed, Duplex, ParseBuf};
+use tokio_proto::pipeline::{Frame, Message};
+use tokio_service::Service;
+use std::cell::RefCell;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+type Body = Empty<(), io::Error>;
+
+type Client = pipeline::Client<String, String, Body, io::Error>;
+
+#[test]
+fn test_pipeline_client_and_server() {
+    let (tx, rx) = oneshot();
+    let (tx2, rx2) = mpsc::channel();
+
+    let t = thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let handle = lp.handle();
+
+        let (client_io, server_io) = duplex();
+
+        let service = tokio_service::simple_service(|req: String| {
+            let resp: Message<String, Body> = Message::WithoutBody(req.to_uppercase());
+            futures::finished(resp)
+        });
+
+        let server = pipeline::Server::new(service, framed(server_io)).unwrap();
+        handle.spawn(server.map_err(|_| ()));
+
+        let transport = RefCell::new(Some(framed(client_io)));
+
+        let client: Client = pipeline::connect(&handle, move || {
+            Ok(transport.borrow_mut().take().unwrap())
+        }).unwrap();
+
+        tx2.send(client).unwrap();
+        lp.run(rx)
+    });
+
+    let client = rx2.recv().unwrap();
+
+    let one = client.call(Message::WithoutBody("hello".to_string()));
+    let two = client.call(Message::WithoutBody("world".to_string()));
+
+    assert_eq!("HELLO", one.wait().unwrap());
+    assert_eq!("WORLD", two.wait().unwrap());
+
+    tx.complete(());
+    t.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_closing_one_end() {
+    use std::io::{Read, Write};
+
+    let (mut a, b) = duplex();
+
+    a.write_all(b"hello").unwrap();
+    drop(b);
+
+    // The other end is gone
+    assert_eq!(io::ErrorKind::BrokenPipe, a.write(b"world").unwrap_err().kind());
+
+    let (mut a, mut b) = duplex();
+
+    a.write_all(b"hello").unwrap();
+    drop(a);
+
+    // Buffered bytes are still read before EOF
+    let mut out = vec![];
+    b.read_to_end(&mut out).unwrap();
+    assert_eq!(b"hello", &out[..]);
+}
+
+fn framed(io: Duplex) -> CodecFramed<Duplex, Lines> {
+    CodecFramed::with_codec(io, Lines)
+}
+
+/// Pipeline frames as `\n` terminated lines
+struct Lines;
+
+impl Codec for Lines {
+    type Out = Frame<String, (), io::Error>;
+    type In = Frame<String, (), io::Error>;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        let n = match buf.find_byte(b'\n') {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let mut line = vec![0; n + 1];
+        buf.peek_slice(&mut line);
+        buf.drop(n + 1);
+
+        match String::from_utf8(line) {
+            Ok(mut s) => {
+                s.pop();
+                Ok(Some(Frame::Message(s)))
+            }
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8")),
+        }
+    }
+
+    fn done(&mut self, _: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        Ok(Some(Frame::Done))
+    }
+
+    fn serialize(&mut self, frame: Self::In, buf: &mut BlockBuf) -> io::Result<()> {
+        match frame {
+            Frame::Message(line) => {
+                buf.write_slice(line.as_bytes());
+                buf.write_slice(b"\n");
+            }
+            Frame::Done => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported frame")),
+        }
+
+        Ok(())
+    }
+}
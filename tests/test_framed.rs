@@ -0,0 +1,505 @@
+extern crate bytes;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+
+use bytes::{BlockBuf, Buf, Bytes, MutBuf};
+use futures::{Async, Future, Sink};
+use futures::stream::Stream;
+use tokio_core::io::{Io, FramedIo};
+use tokio_proto::{Codec, CodecFramed, FlushPolicy, Framed, Parse, ParseBuf, Serialize, WriteVec};
+use std::cell::RefCell;
+use std::io::{self, Cursor, Read, Write};
+use std::rc::Rc;
+use std::str;
+
+#[test]
+fn test_reading_lines() {
+    let mut framed = framed(b"hello\nworld\n");
+
+    assert_eq!("hello", ready(framed.read().unwrap()));
+    assert_eq!("world", ready(framed.read().unwrap()));
+    assert!(!framed.read().unwrap().is_ready());
+}
+
+#[test]
+fn test_reading_malformed_line() {
+    let mut framed = framed(b"hello\n\xff\xfe\n");
+
+    assert_eq!("hello", ready(framed.read().unwrap()));
+
+    let err = framed.read().unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn test_reading_partial_line_at_eof() {
+    let mut framed = framed(b"hello");
+
+    let err = framed.read().unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
+
+#[test]
+fn test_writing_lines() {
+    let (mut framed, written) = framed_with_output(b"");
+
+    framed.write("hello".to_string()).unwrap();
+    framed.write("world".to_string()).unwrap();
+    assert!(framed.flush().unwrap().is_ready());
+
+    assert_eq!(b"hello\nworld\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_writing_unrepresentable_line() {
+    let (mut framed, written) = framed_with_output(b"");
+
+    let err = framed.write("hello\nworld".to_string()).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+    // The transport is still usable
+    framed.write("hello".to_string()).unwrap();
+    assert!(framed.flush().unwrap().is_ready());
+
+    assert_eq!(b"hello\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_flushing_immediately() {
+    let (mut framed, written) = framed_with_output(b"");
+    framed.set_flush_policy(FlushPolicy::Immediate);
+
+    assert!(framed.write("hello".to_string()).unwrap().is_ready());
+    assert_eq!(b"hello\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_flushing_after_n_frames() {
+    let (mut framed, written) = framed_with_output(b"");
+    framed.set_flush_policy(FlushPolicy::Frames(2));
+
+    assert!(!framed.write("hello".to_string()).unwrap().is_ready());
+    assert!(written.borrow().is_empty());
+
+    assert!(framed.write("world".to_string()).unwrap().is_ready());
+    assert_eq!(b"hello\nworld\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_flushing_after_n_bytes() {
+    let (mut framed, written) = framed_with_output(b"");
+    framed.set_flush_policy(FlushPolicy::Bytes(8));
+
+    framed.write("one".to_string()).unwrap();
+    assert!(written.borrow().is_empty());
+
+    framed.write("two".to_string()).unwrap();
+    assert_eq!(b"one\ntwo\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_flushing_when_block_fills() {
+    let (io, written) = mock(b"");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::new(8, 8));
+    framed.set_flush_policy(FlushPolicy::BlockFull);
+
+    framed.write("hello".to_string()).unwrap();
+    assert!(written.borrow().is_empty());
+
+    // Spills over into a second block
+    framed.write("world".to_string()).unwrap();
+    assert_eq!(b"hello\nworld\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_codec_sharing_state() {
+    let (io, written) = mock(b"one\ntwo\n");
+    let mut framed = CodecFramed::with_codec(io, Numbered { lines: 0 });
+
+    assert_eq!("one", ready(framed.read().unwrap()));
+    assert_eq!("two", ready(framed.read().unwrap()));
+
+    framed.write("three".to_string()).unwrap();
+    assert!(framed.flush().unwrap().is_ready());
+
+    assert_eq!(b"2 three\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_split() {
+    let (io, written) = mock(b"one\ntwo\n");
+    let framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::default());
+
+    let (rd, wr) = framed.split();
+
+    let lines = rd.collect().wait().unwrap();
+    assert_eq!(lines, ["one", "two"]);
+
+    wr.send("three".to_string()).wait().unwrap();
+    assert_eq!(b"three\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_split_codec() {
+    let (io, written) = mock(b"one\ntwo\n");
+    let framed = CodecFramed::with_codec(io, Numbered { lines: 0 });
+
+    let (rd, wr) = framed.split();
+
+    let lines = rd.collect().wait().unwrap();
+    assert_eq!(lines, ["one", "two"]);
+
+    // Both halves share the codec state
+    wr.send("three".to_string()).wait().unwrap();
+    assert_eq!(b"2 three\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_split_write_half_after_unrepresentable_line() {
+    let (io, written) = mock(b"");
+    let framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::default());
+
+    let (_, mut wr) = framed.split();
+
+    let err = wr.start_send("hello\nworld".to_string()).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+    // Only the line failed, the sink is still usable
+    wr.send("hello".to_string()).wait().unwrap();
+    assert_eq!(b"hello\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_split_after_vectored_writes() {
+    let (io, written) = mock(b"");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::new(8, 4));
+    framed.set_vectored_writes(true);
+
+    // Buffered across blocks, but not flushed
+    framed.write("hello".to_string()).unwrap();
+    framed.write("world".to_string()).unwrap();
+    assert!(written.borrow().is_empty());
+
+    let (_, wr) = framed.split();
+
+    // The write half writes the carried over blocks one at a time
+    wr.send("bye".to_string()).wait().unwrap();
+    assert_eq!(b"hello\nworld\nbye\n", &written.borrow()[..]);
+}
+
+#[test]
+fn test_switching_codecs() {
+    let (io, written) = mock(b"upgrade\nhello\n");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::default());
+
+    assert_eq!("upgrade", ready(framed.read().unwrap()));
+
+    // The rest of the input has already been read into the buffer
+    let (io, _, rd) = framed.into_parts();
+    assert!(!rd.is_empty());
+
+    let mut framed = CodecFramed::with_buffers(io, Numbered { lines: 0 }, rd, BlockBuf::default());
+
+    assert_eq!("hello", ready(framed.read().unwrap()));
+
+    framed.write("world".to_string()).unwrap();
+    assert!(framed.flush().unwrap().is_ready());
+
+    assert_eq!(b"1 world\n", &written.borrow()[..]);
+    // All input was consumed from the `Io` before switching
+    assert_eq!(14, framed.get_ref().rd.position());
+}
+
+#[test]
+fn test_writing_across_blocks() {
+    let (io, written) = mock(b"");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::new(8, 4));
+
+    framed.write("hello".to_string()).unwrap();
+    framed.write("world".to_string()).unwrap();
+    assert!(framed.flush().unwrap().is_ready());
+
+    assert_eq!(b"hello\nworld\n", &written.borrow()[..]);
+
+    // Without vectored writes, the blocks are written one at a time
+    assert!(framed.get_ref().vec_writes.is_empty());
+}
+
+#[test]
+fn test_vectored_writes() {
+    let (io, written) = mock(b"");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::new(8, 4));
+    framed.set_vectored_writes(true);
+
+    framed.write("hello".to_string()).unwrap();
+    framed.write("world".to_string()).unwrap();
+    assert!(framed.flush().unwrap().is_ready());
+
+    assert_eq!(b"hello\nworld\n", &written.borrow()[..]);
+
+    // A single write carried all three blocks
+    assert_eq!(vec![3], framed.get_ref().vec_writes);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_vectored_writes_to_socket() {
+    use tokio_core::io::read_to_end;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let client = TcpStream::connect(&listener.local_addr().unwrap(), &handle);
+    let server = listener.incoming().take(1).collect().map(|mut sockets| sockets.remove(0).0);
+
+    let (client, server) = core.run(client.join(server)).unwrap();
+
+    let mut framed = Framed::new(client, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::new(8, 4));
+    framed.set_vectored_writes(true);
+
+    framed.write("hello".to_string()).unwrap();
+    framed.write("world".to_string()).unwrap();
+
+    // The client is closed once flushed, so the server reads until EOF
+    let (_, (_, written)) = core.run(Flush(Some(framed)).join(read_to_end(server, vec![]))).unwrap();
+    assert_eq!(b"hello\nworld\n", &written[..]);
+}
+
+#[test]
+fn test_parsing_across_blocks_without_copying() {
+    let mut buf = BlockBuf::new(8, 4);
+    buf.write_slice(b"hello\nworld\n");
+
+    assert_eq!(Some(5), buf.find_byte(b'\n'));
+    assert_eq!(None, buf.find_byte(b'!'));
+
+    let mut head = [0; 6];
+    assert!(buf.peek_slice(&mut head));
+    assert_eq!(b"hello\n", &head);
+
+    // Nothing was consumed
+    assert_eq!(12, buf.len());
+
+    let line = buf.shift(6);
+    assert_eq!(6, line.len());
+    assert_eq!(Some(5), buf.find_byte(b'\n'));
+
+    let mut rest = [0; 7];
+    assert!(!buf.peek_slice(&mut rest));
+}
+
+#[test]
+fn test_parsing_shared_slices() {
+    let (io, _) = mock(b"hello\nworld\n");
+    let mut framed = Framed::new(io, SliceParser, LineSerializer, BlockBuf::default(), BlockBuf::default());
+
+    let hello = ready(framed.read().unwrap());
+    assert_eq!(b"hello", hello.buf().bytes());
+
+    // The frame was not copied out of the read buffer: the rest of the input
+    // directly follows it in memory
+    let (_, _, rd) = framed.into_parts();
+    assert_eq!(b"world\n", rd.buf().bytes());
+    assert_eq!(hello.buf().bytes().as_ptr() as usize + 6, rd.buf().bytes().as_ptr() as usize);
+}
+
+#[test]
+fn test_growing_read_buffer() {
+    let (io, _) = mock(b"this line does not fit in the read buffer\nshort\n");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::new(2, 4), BlockBuf::default());
+
+    assert_eq!("this line does not fit in the read buffer", ready(framed.read().unwrap()));
+    assert_eq!("short", ready(framed.read().unwrap()));
+}
+
+#[test]
+fn test_frame_exceeding_max_read_buffer() {
+    let (io, _) = mock(b"this line does not fit in the read buffer\n");
+    let mut framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::new(2, 4), BlockBuf::default());
+
+    framed.set_max_read_buf(16);
+
+    let err = framed.read().unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+}
+
+/// Parses `\n` terminated UTF-8 lines
+struct LineParser;
+
+impl Parse for LineParser {
+    type Out = String;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        if !buf.is_compact() {
+            buf.compact();
+        }
+
+        let n = match buf.bytes().and_then(|b| b.iter().position(|&b| b == b'\n')) {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let line = {
+            let bytes = buf.bytes().unwrap();
+
+            match str::from_utf8(&bytes[..n]) {
+                Ok(s) => s.to_string(),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8")),
+            }
+        };
+
+        buf.drop(n + 1);
+        Ok(Some(line))
+    }
+
+    fn done(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-line"))
+        }
+    }
+}
+
+/// Parses `\n` terminated lines into slices of the read buffer
+struct SliceParser;
+
+impl Parse for SliceParser {
+    type Out = Bytes;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Bytes>> {
+        Ok(buf.take_until(b'\n'))
+    }
+}
+
+/// Serializes lines, appending a `\n`
+struct LineSerializer;
+
+impl Serialize for LineSerializer {
+    type In = String;
+
+    fn serialize(&mut self, msg: String, buf: &mut BlockBuf) -> io::Result<()> {
+        if msg.contains('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "line contains a newline"));
+        }
+
+        buf.write_slice(msg.as_bytes());
+        buf.write_slice(b"\n");
+        Ok(())
+    }
+}
+
+/// Line codec prefixing each written line with the number of lines read
+struct Numbered {
+    lines: usize,
+}
+
+impl Codec for Numbered {
+    type Out = String;
+    type In = String;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<String>> {
+        let line = try!(LineParser.parse(buf));
+
+        if line.is_some() {
+            self.lines += 1;
+        }
+
+        Ok(line)
+    }
+
+    fn serialize(&mut self, msg: String, buf: &mut BlockBuf) -> io::Result<()> {
+        LineSerializer.serialize(format!("{} {}", self.lines, msg), buf)
+    }
+}
+
+/// An in-memory `Io` that reads from a fixed buffer and collects all writes
+struct Mock {
+    rd: Cursor<Vec<u8>>,
+    wr: Rc<RefCell<Vec<u8>>>,
+    // Number of buffers handed to each vectored write
+    vec_writes: Vec<usize>,
+}
+
+impl Read for Mock {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        self.rd.read(dst)
+    }
+}
+
+impl Write for Mock {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.wr.borrow_mut().write(src)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteVec for Mock {
+    fn write_vec(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.vec_writes.push(bufs.len());
+        self.wr.borrow_mut().write_vec(bufs)
+    }
+}
+
+impl Io for Mock {
+    fn poll_read(&mut self) -> Async<()> {
+        Async::Ready(())
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        Async::Ready(())
+    }
+}
+
+/// Flushes a transport, then drops it
+struct Flush<T>(Option<T>);
+
+impl<T: FramedIo> Future for Flush<T> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> futures::Poll<(), io::Error> {
+        if !try!(self.0.as_mut().unwrap().flush()).is_ready() {
+            return Ok(Async::NotReady);
+        }
+
+        self.0 = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+fn framed(input: &[u8]) -> Framed<Mock, LineParser, LineSerializer> {
+    framed_with_output(input).0
+}
+
+fn framed_with_output(input: &[u8]) -> (Framed<Mock, LineParser, LineSerializer>, Rc<RefCell<Vec<u8>>>) {
+    let (io, written) = mock(input);
+    let framed = Framed::new(io, LineParser, LineSerializer, BlockBuf::default(), BlockBuf::default());
+    (framed, written)
+}
+
+fn mock(input: &[u8]) -> (Mock, Rc<RefCell<Vec<u8>>>) {
+    let written = Rc::new(RefCell::new(vec![]));
+
+    let io = Mock {
+        rd: Cursor::new(input.to_vec()),
+        wr: written.clone(),
+        vec_writes: vec![],
+    };
+
+    (io, written)
+}
+
+fn ready<T>(v: Async<T>) -> T {
+    match v {
+        Async::Ready(v) => v,
+        Async::NotReady => panic!("expected ready"),
+    }
+}
@@ -0,0 +1,150 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+
+use futures::{Async, Future, Poll};
+use futures::stream::Stream;
+use tokio_proto::{Peekable, ReadVec, TryRead};
+use std::io::{self, Read};
+
+#[test]
+fn test_reading_into_bufs() {
+    let mut src: &[u8] = b"hello world";
+
+    let mut a = [0; 5];
+    let mut b = [0; 3];
+
+    let n = src.read_vec(&mut [&mut a[..], &mut b[..]]).unwrap();
+
+    assert_eq!(8, n);
+    assert_eq!(b"hello", &a);
+    assert_eq!(b" wo", &b);
+    assert_eq!(b"rld", src);
+}
+
+#[test]
+fn test_peeking_does_not_consume() {
+    let mut io = Peekable::new(Chunks(vec![b"hel".to_vec()]));
+
+    let mut buf = [0; 5];
+    assert_eq!(3, io.peek(&mut buf).unwrap());
+    assert_eq!(b"hel", &buf[..3]);
+
+    io.get_mut().0.push(b"lo".to_vec());
+
+    assert_eq!(5, io.peek(&mut buf).unwrap());
+    assert_eq!(b"hello", &buf);
+    assert_eq!(b"hello", io.buffered());
+
+    let mut out = [0; 5];
+    io.read_exact(&mut out).unwrap();
+    assert_eq!(b"hello", &out);
+    assert!(io.buffered().is_empty());
+}
+
+#[test]
+fn test_peeking_would_block() {
+    let mut io = Peekable::new(Chunks(vec![]));
+
+    let mut buf = [0; 4];
+    assert_eq!(Async::NotReady, io.try_peek(&mut buf).unwrap());
+}
+
+#[test]
+fn test_peekable_with_prefix() {
+    let mut io = Peekable::with_prefix(&b"world"[..], b"hello ".to_vec());
+
+    let mut out = String::new();
+    io.read_to_string(&mut out).unwrap();
+    assert_eq!("hello world", out);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_peeking_at_socket() {
+    use tokio_proto::Peek;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    client.write_all(b"ping").unwrap();
+
+    // The peek may return before all the bytes made it through, in which
+    // case it is retried
+    let mut buf = [0; 4];
+
+    while Peek::peek(&mut server, &mut buf).unwrap() < 4 {
+        thread::yield_now();
+    }
+
+    assert_eq!(b"ping", &buf);
+
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(b"ping", &buf);
+}
+
+#[test]
+fn test_peeking_at_reactor_socket() {
+    use std::io::Write;
+    use std::net;
+    use tokio_core::io::read_exact;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let server = listener.incoming().take(1).collect().map(|mut sockets| sockets.remove(0).0);
+    let server = core.run(server).unwrap();
+
+    client.write_all(b"ping").unwrap();
+
+    // The reactor has not reported the socket as readable yet, so the first
+    // peek registers interest and waits to be notified
+
+    let (server, peeked) = core.run(PeekAt(Some(server), [0; 4])).unwrap();
+    assert_eq!(b"ping", &peeked);
+
+    let (_, buf) = core.run(read_exact(server, [0; 4])).unwrap();
+    assert_eq!(b"ping", &buf);
+}
+
+/// Returns one chunk per read, then would-block
+struct Chunks(Vec<Vec<u8>>);
+
+impl Read for Chunks {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.0.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        let chunk = self.0.remove(0);
+        dst[..chunk.len()].copy_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+}
+
+/// Peeks at a socket, returning it along with the peeked bytes
+struct PeekAt<T>(Option<T>, [u8; 4]);
+
+impl<T: TryRead + tokio_proto::Peek> Future for PeekAt<T> {
+    type Item = (T, [u8; 4]);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(T, [u8; 4]), io::Error> {
+        // Written at once, so the bytes are peeked at together
+        match try!(self.0.as_mut().unwrap().try_peek(&mut self.1)) {
+            Async::Ready(n) => assert_eq!(4, n),
+            Async::NotReady => return Ok(Async::NotReady),
+        }
+
+        Ok(Async::Ready((self.0.take().unwrap(), self.1)))
+    }
+}
@@ -0,0 +1,223 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use futures::{failed, finished, oneshot, Async, BoxFuture, Future};
+use futures::executor::{self, Unpark};
+use futures::stream::{self, Empty, Receiver};
+use tokio_core::reactor::Core;
+use tokio_proto::middleware::{CatchPanic, ConcurrencyLimit, Log, Retry, Timeout};
+use tokio_proto::pipeline::{self, Message};
+use tokio_service::Service;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+type Body = Empty<u32, io::Error>;
+
+type Msg = Message<&'static str, Body>;
+
+#[test]
+fn test_timeout() {
+    let mut lp = Core::new().unwrap();
+
+    // Never responds
+    let service = tokio_service::simple_service(|_: Msg| futures::empty::<Msg, io::Error>());
+    let service = Timeout::new(service, Duration::from_millis(10), &lp.handle());
+
+    let err = lp.run(service.call(msg("hello"))).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+
+    // Responses within the timeout pass through
+    let service = tokio_service::simple_service(|req: Msg| finished::<Msg, io::Error>(req));
+    let service = Timeout::new(service, Duration::from_secs(5), &lp.handle());
+
+    assert_eq!("hello", unwrap_msg(lp.run(service.call(msg("hello"))).unwrap()));
+}
+
+#[test]
+fn test_concurrency_limit() {
+    let pending = Arc::new(Mutex::new(vec![]));
+    let pending2 = pending.clone();
+
+    let service = tokio_service::simple_service(move |_: Msg| {
+        let (c, rx) = oneshot();
+        pending2.lock().unwrap().push(c);
+        rx.then(|res| res.map_err(|_| io::Error::new(io::ErrorKind::Other, "canceled")))
+    });
+
+    let service = ConcurrencyLimit::new(service, 1);
+
+    let mut one = executor::spawn(service.call(msg("one")));
+    let mut two = executor::spawn(service.call(msg("two")));
+
+    // Only the first request is passed to the service
+    assert!(one.poll_future(unpark()).unwrap().is_not_ready());
+    assert!(two.poll_future(unpark()).unwrap().is_not_ready());
+    assert_eq!(1, pending.lock().unwrap().len());
+    assert_eq!(1, service.in_flight());
+    assert!(service.poll_ready().is_not_ready());
+
+    let c = pending.lock().unwrap().remove(0);
+    c.complete(Message::WithoutBody("one"));
+
+    assert_eq!("one", unwrap_msg(ready(one.poll_future(unpark()).unwrap())));
+
+    // Now the second request proceeds
+    assert!(two.poll_future(unpark()).unwrap().is_not_ready());
+    assert_eq!(1, pending.lock().unwrap().len());
+
+    // Dropping the response releases the limit
+    drop(two);
+    assert_eq!(0, service.in_flight());
+    assert!(service.poll_ready().is_ready());
+}
+
+#[test]
+fn test_retry() {
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let service = Retry::new(Flaky::new(&calls, 2), 2);
+
+    assert_eq!("hello", unwrap_msg(service.call(msg("hello")).wait().unwrap()));
+    assert_eq!(3, calls.load(Ordering::SeqCst));
+
+    // Out of retries
+    calls.store(0, Ordering::SeqCst);
+
+    let mut service = Retry::new(Flaky::new(&calls, 2), 1);
+    assert!(service.call(msg("hello")).wait().is_err());
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+
+    // Not a retryable error
+    calls.store(0, Ordering::SeqCst);
+
+    service.set_should_retry(|e: &io::Error| e.kind() == io::ErrorKind::TimedOut);
+    assert!(service.call(msg("hello")).wait().is_err());
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_retry_skips_requests_with_body() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let service = tokio_service::simple_service(move |_: Message<&'static str, Receiver<u32, io::Error>>| {
+        calls2.fetch_add(1, Ordering::SeqCst);
+        failed::<Msg, io::Error>(io::Error::new(io::ErrorKind::Other, "oops"))
+    });
+
+    let service = Retry::new(service, 2);
+
+    let (_tx, body) = stream::channel();
+    assert!(service.call(Message::WithBody("hello", body)).wait().is_err());
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_catch_panic() {
+    let service = CatchPanic::new(tokio_service::simple_service(|req: Msg| {
+        if let Message::WithoutBody("call") = req {
+            panic!("panicked in call");
+        }
+
+        futures::lazy(move || {
+            if let Message::WithoutBody("poll") = req {
+                panic!("panicked in poll");
+            }
+
+            finished::<Msg, io::Error>(req)
+        })
+    }));
+
+    let err = service.call(msg("call")).wait().unwrap_err();
+    assert_eq!("service panicked: panicked in call", err.to_string());
+
+    let err = service.call(msg("poll")).wait().unwrap_err();
+    assert_eq!("service panicked: panicked in poll", err.to_string());
+
+    assert_eq!("hello", unwrap_msg(service.call(msg("hello")).wait().unwrap()));
+}
+
+#[test]
+fn test_composing_middleware() {
+    let mut lp = Core::new().unwrap();
+
+    let service = tokio_service::simple_service(|req: Msg| finished::<Msg, io::Error>(req));
+
+    let service = Log::new("echo",
+                           Timeout::new(ConcurrencyLimit::new(CatchPanic::new(service), 8),
+                                        Duration::from_secs(5),
+                                        &lp.handle()));
+
+    // Usable as a pipeline server service
+    assert_server_service(&service);
+
+    assert_eq!("hello", unwrap_msg(lp.run(service.call(msg("hello"))).unwrap()));
+}
+
+fn assert_server_service<S: pipeline::ServerService>(_: &S) {}
+
+fn msg(msg: &'static str) -> Msg {
+    Message::WithoutBody(msg)
+}
+
+fn unwrap_msg(msg: Msg) -> &'static str {
+    match msg {
+        Message::WithoutBody(msg) => msg,
+        _ => panic!("expected a message without body"),
+    }
+}
+
+fn ready<T>(res: Async<T>) -> T {
+    match res {
+        Async::Ready(val) => val,
+        Async::NotReady => panic!("expected the future to be ready"),
+    }
+}
+
+/// Echoes requests, after failing a number of them
+struct Flaky {
+    calls: Arc<AtomicUsize>,
+    failures: usize,
+}
+
+impl Flaky {
+    fn new(calls: &Arc<AtomicUsize>, failures: usize) -> Flaky {
+        Flaky {
+            calls: calls.clone(),
+            failures: failures,
+        }
+    }
+}
+
+impl Service for Flaky {
+    type Request = Msg;
+    type Response = Msg;
+    type Error = io::Error;
+    type Future = BoxFuture<Msg, io::Error>;
+
+    fn call(&self, req: Msg) -> Self::Future {
+        if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+            failed(io::Error::new(io::ErrorKind::Other, "oops")).boxed()
+        } else {
+            finished(req).boxed()
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        Async::Ready(())
+    }
+}
+
+fn unpark() -> Arc<Unpark> {
+    struct Noop;
+
+    impl Unpark for Noop {
+        fn unpark(&self) {}
+    }
+
+    Arc::new(Noop)
+}
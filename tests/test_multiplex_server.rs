@@ -10,16 +10,18 @@ extern crate env_logger;
 
 mod support;
 
-use futures::stream::{Receiver};
-use futures::{Future, finished, oneshot};
+use futures::stream::{self, Receiver, Stream};
+use futures::{Async, Finished, Future, finished, oneshot};
 use support::mock;
 use tokio_proto::multiplex::{self, RequestId, Frame, Message};
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle};
+use tokio_service::Service;
 use rand::Rng;
 use std::io;
 use std::sync::{mpsc, Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 
 // The message type is a static string for both the request and response
 type Msg = &'static str;
@@ -321,6 +323,335 @@ fn test_reaching_max_in_flight_requests() {
 fn test_reaching_max_buffered_frames() {
 }
 
+#[test]
+fn test_fifo_schedule_writes_bodies_to_completion() {
+    run_with_schedule(WithBody, multiplex::Fifo::new(), |mock| {
+        assert_eq!(written(&mock, &["big", "small"], 5),
+                   ["0:big", "0:1", "0:2", "0:eof", "1:small"]);
+    });
+}
+
+#[test]
+fn test_round_robin_schedule_interleaves_bodies() {
+    run_with_schedule(WithBody, multiplex::RoundRobin::new(), |mock| {
+        assert_eq!(written(&mock, &["big", "small"], 5),
+                   ["0:big", "1:small", "0:1", "0:2", "0:eof"]);
+    });
+}
+
+#[test]
+fn test_weighted_schedule() {
+    let schedule = multiplex::Weighted::new(|req: &Message<Msg, Body>| {
+        match *req {
+            Message::WithoutBody("heavy") => 2,
+            _ => 1,
+        }
+    });
+
+    run_with_schedule(WithBody, schedule, |mock| {
+        assert_eq!(written(&mock, &["heavy", "light"], 8),
+                   ["0:heavy", "0:1", "1:light", "0:2", "0:eof", "1:1", "1:2", "1:eof"]);
+    });
+}
+
+#[test]
+fn test_request_body_without_flow_control() {
+    let (tx, rx) = channel();
+
+    let service = tokio_service::simple_service(move |req: Message<Msg, Body>| {
+        match req {
+            Message::WithBody(_, body) => tx.lock().unwrap().send(body).unwrap(),
+            _ => panic!("expected a request body"),
+        }
+
+        // Never respond
+        futures::empty::<Message<Msg, Body>, io::Error>()
+    });
+
+    run(service, |mock| {
+        let (sender, body) = stream::channel();
+        mock.send(Frame::MessageWithBody(0, Message::WithBody("upload", body), sender));
+
+        // More chunks than any window, none of which are acknowledged
+        for i in 1..20 {
+            mock.send(Frame::Body(0, Some(i)));
+        }
+
+        mock.send(Frame::Body(0, None));
+
+        let body = rx.recv().unwrap();
+        let chunks: Vec<u32> = body.wait().map(|chunk| chunk.unwrap()).collect();
+        assert_eq!((1..20).collect::<Vec<u32>>(), chunks);
+
+        mock.allow_write();
+        mock.assert_no_write(20);
+    });
+}
+
+#[test]
+fn test_request_body_chunks_grant_credit_as_consumed() {
+    let (tx, rx) = channel();
+
+    let service = tokio_service::simple_service(move |req: Message<Msg, Body>| {
+        match req {
+            Message::WithBody(_, body) => tx.lock().unwrap().send(body).unwrap(),
+            _ => panic!("expected a request body"),
+        }
+
+        // Never respond
+        futures::empty::<Message<Msg, Body>, io::Error>()
+    });
+
+    run_with(service, |server, _| server.set_flow_control_windows(2, 4).unwrap(), |mock| {
+        let (sender, body) = stream::channel();
+        mock.send(Frame::MessageWithBody(0, Message::WithBody("upload", body), sender));
+        mock.send(Frame::Body(0, Some(1)));
+        mock.send(Frame::Body(0, Some(2)));
+
+        let body = rx.recv().unwrap();
+        support::sleep_ms(20);
+
+        // The first chunk is handed to the body stream right away, the second
+        // one waits for the first to be consumed.
+        assert_eq!(next_writes(&mock, 2), ["window:0:1", "window:conn:1"]);
+        mock.assert_no_write(20);
+
+        let mut body = body.wait();
+        assert_eq!(1, body.next().unwrap().unwrap());
+
+        assert_eq!(next_writes(&mock, 2), ["window:0:1", "window:conn:1"]);
+        mock.assert_no_write(20);
+
+        // The stream window is 2, the third chunk exceeds it
+        mock.send(Frame::Body(0, Some(3)));
+        mock.send(Frame::Body(0, Some(4)));
+        mock.send(Frame::Body(0, Some(5)));
+        mock.assert_drop();
+    });
+}
+
+#[test]
+fn test_response_body_waits_for_window_updates() {
+    run_with(WithBody, |server, _| server.set_flow_control_windows(2, 1).unwrap(), |mock| {
+        assert_eq!(written(&mock, &["big"], 2), ["0:big", "0:1"]);
+
+        // Out of connection credit
+        mock.allow_write();
+        mock.assert_no_write(20);
+
+        mock.send(Frame::WindowUpdate(None, 1));
+        assert_eq!(frame_str(mock.next_write()), "0:2");
+
+        // Out of both stream and connection credit
+        mock.allow_write();
+        mock.send(Frame::WindowUpdate(Some(0), 1));
+        mock.assert_no_write(20);
+
+        mock.send(Frame::WindowUpdate(None, 1));
+        assert_eq!(frame_str(mock.next_write()), "0:eof");
+    });
+}
+
+#[test]
+fn test_reusing_request_id_drops_previous_response() {
+    let (tx, rx) = channel();
+
+    let service = tokio_service::simple_service(move |_| {
+        let (c, resp) = oneshot();
+        tx.lock().unwrap().send(c).unwrap();
+        resp.map_err(|_| io::Error::new(io::ErrorKind::Other, "canceled"))
+    });
+
+    run(service, |mock| {
+        mock.send(msg(0, "first"));
+        let first = rx.recv().unwrap();
+
+        // The peer reuses the id while the first request is in flight
+        mock.send(msg(0, "second"));
+        let second = rx.recv().unwrap();
+
+        // The first response can no longer be told apart, it is dropped
+        first.complete("first");
+        mock.allow_write();
+        mock.assert_no_write(20);
+
+        second.complete("second");
+        assert_eq!(frame_str(mock.next_write()), "0:second");
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_rejecting_invalid_flow_control_windows() {
+    let service = tokio_service::simple_service(|req| {
+        finished(req)
+    });
+
+    run_with(service, |server, _| {
+        let err = server.set_flow_control_windows(0, 4).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let err = server.set_flow_control_windows(2, u32::MAX as usize).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }, |mock| {
+        // Flow control stayed disabled
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_keepalive() {
+    let service = tokio_service::simple_service(|req| {
+        finished(req)
+    });
+
+    run_with(service, |server, handle| {
+        server.set_keepalive(Duration::from_millis(20), Duration::from_millis(50), handle);
+    }, |mock| {
+        // Pings are answered
+        mock.send(Frame::Ping);
+        assert_eq!(next_writes(&mock, 1), ["pong"]);
+
+        // The connection is pinged once idle
+        assert_eq!(next_writes(&mock, 1), ["ping"]);
+
+        // The peer does not answer
+        mock.assert_drop();
+    });
+}
+
+#[test]
+fn test_keepalive_stops_once_done_reading() {
+    let (tx, rx) = channel();
+
+    let service = tokio_service::simple_service(move |_| {
+        let (c, fut) = oneshot();
+        tx.lock().unwrap().send(c).unwrap();
+        fut.then(|r| r.unwrap())
+    });
+
+    run_with(service, |server, handle| {
+        server.set_keepalive(Duration::from_millis(20), Duration::from_millis(50), handle);
+    }, |mock| {
+        mock.send(msg(0, "one"));
+        mock.send(Frame::Done);
+        let c = rx.recv().unwrap();
+
+        // Answers are no longer read, the peer is not pinged while the
+        // response is delayed
+        mock.allow_write();
+        mock.assert_no_write(100);
+
+        c.complete(Ok(Message::WithoutBody("one")));
+        assert_eq!(frame_str(mock.next_write()), "0:one");
+
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_draining_connection() {
+    let (tx, rx) = channel();
+
+    let service = tokio_service::simple_service(move |_| {
+        let (c, fut) = oneshot();
+        tx.lock().unwrap().send(c).unwrap();
+        fut.then(|r| r.unwrap())
+    });
+
+    let (drain_tx, drain_rx) = mpsc::channel();
+
+    run_with(service, move |server, _| {
+        server.set_goaway(true);
+        drain_tx.send(server.drain_handle()).unwrap();
+    }, |mock| {
+        let drain = drain_rx.recv().unwrap();
+
+        mock.send(msg(0, "one"));
+        let c = rx.recv().unwrap();
+
+        drain.drain();
+        assert_eq!(next_writes(&mock, 1), ["goaway"]);
+
+        // Requests are no longer read
+        mock.send(msg(1, "two"));
+        mock.allow_write();
+        mock.assert_no_write(20);
+
+        // The in-flight response is written before the connection is closed
+        c.complete(Ok(Message::WithoutBody("one")));
+        assert_eq!(frame_str(mock.next_write()), "0:one");
+
+        mock.allow_and_assert_drop();
+    });
+}
+
+type ChunkStream = stream::Iter<::std::vec::IntoIter<Result<u32, io::Error>>>;
+
+/// Responds with a body of two chunks, unless the request is "small"
+struct WithBody;
+
+impl Service for WithBody {
+    type Request = Message<Msg, Body>;
+    type Response = Message<Msg, ChunkStream>;
+    type Error = io::Error;
+    type Future = Finished<Self::Response, io::Error>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let msg = match req {
+            Message::WithoutBody(msg) => msg,
+            Message::WithBody(msg, _) => msg,
+        };
+
+        if msg == "small" {
+            finished(Message::WithoutBody(msg))
+        } else {
+            finished(Message::WithBody(msg, stream::iter(vec![Ok(1), Ok(2)])))
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        Async::Ready(())
+    }
+}
+
+// Sends the requests, waits for all of them to complete, then returns the
+// first `n` frames written.
+fn written(mock: &mock::TransportHandle<InFrame, OutFrame>, reqs: &[Msg], n: usize) -> Vec<String> {
+    for (i, req) in reqs.iter().enumerate() {
+        mock.send(msg(i as RequestId, *req));
+    }
+
+    support::sleep_ms(20);
+
+    next_writes(mock, n)
+}
+
+// Allows and returns the next `n` frames written
+fn next_writes(mock: &mock::TransportHandle<InFrame, OutFrame>, n: usize) -> Vec<String> {
+    (0..n).map(|_| {
+        mock.allow_write();
+        frame_str(mock.next_write())
+    }).collect()
+}
+
+fn frame_str(frame: InFrame) -> String {
+    match frame {
+        Frame::Message(id, msg) => format!("{}:{}", id, msg),
+        Frame::Body(id, Some(chunk)) => format!("{}:{}", id, chunk),
+        Frame::Body(id, None) => format!("{}:eof", id),
+        Frame::WindowUpdate(Some(id), credit) => format!("window:{}:{}", id, credit),
+        Frame::WindowUpdate(None, credit) => format!("window:conn:{}", credit),
+        Frame::Ping => "ping".to_string(),
+        Frame::Pong => "pong".to_string(),
+        Frame::GoAway => "goaway".to_string(),
+        frame => panic!("unexpected frame; frame={:?}", frame),
+    }
+}
+
 fn channel<T>() -> (Arc<Mutex<mpsc::Sender<T>>>, mpsc::Receiver<T>) {
     let (tx, rx) = mpsc::channel();
     let tx = Arc::new(Mutex::new(tx));
@@ -341,6 +672,34 @@ fn run<S, F>(service: S, f: F)
                                         Error = io::Error> + Send + 'static,
           S::Future: Send + 'static,
           F: FnOnce(mock::TransportHandle<InFrame, OutFrame>),
+{
+    run_with(service, |_, _| {}, f)
+}
+
+fn run_with_schedule<S, B, U, F>(service: S, schedule: U, f: F)
+    where S: multiplex::ServerService<Request = multiplex::Message<Msg, Body>,
+                                     Response = Msg,
+                                         Body = u32,
+                                   BodyStream = B,
+                                        Error = io::Error> + Send + 'static,
+          S::Future: Send + 'static,
+          B: Stream<Item = u32, Error = io::Error>,
+          U: multiplex::Schedule<Message<Msg, Body>> + Send + 'static,
+          F: FnOnce(mock::TransportHandle<InFrame, OutFrame>),
+{
+    run_with(service, move |server, _| server.set_schedule(schedule), f)
+}
+
+fn run_with<S, B, C, F>(service: S, configure: C, f: F)
+    where S: multiplex::ServerService<Request = multiplex::Message<Msg, Body>,
+                                     Response = Msg,
+                                         Body = u32,
+                                   BodyStream = B,
+                                        Error = io::Error> + Send + 'static,
+          S::Future: Send + 'static,
+          B: Stream<Item = u32, Error = io::Error>,
+          C: FnOnce(&mut multiplex::Server<S, mock::Transport<InFrame, OutFrame>>, &Handle) + Send + 'static,
+          F: FnOnce(mock::TransportHandle<InFrame, OutFrame>),
 {
     drop(::env_logger::init());
     let (tx, rx) = oneshot();
@@ -352,7 +711,8 @@ fn run<S, F>(service: S, f: F)
 
         let transport = new_transport.new_transport().unwrap();
         handle.spawn({
-            let dispatch = multiplex::Server::new(service, transport).unwrap();
+            let mut dispatch = multiplex::Server::new(service, transport).unwrap();
+            configure(&mut dispatch, &handle);
             dispatch.map_err(|e| error!("error: {}", e))
         });
         tx2.send(mock).unwrap();
@@ -14,12 +14,15 @@ use futures::stream::{self, Receiver};
 use futures::{Future, oneshot};
 use support::mock;
 use tokio_service::Service;
+use tokio_proto::metrics::Observer;
 use tokio_proto::pipeline;
 use tokio_core::reactor::Core;
 use std::io;
 use std::thread;
 use std::cell::RefCell;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 // Transport handle
 type TransportHandle = mock::TransportHandle<Frame, Frame>;
@@ -98,9 +101,56 @@ fn test_streaming_request_body() {
 fn test_streaming_response_body() {
 }
 
+#[test]
+fn test_keepalive_timeout_fails_pending_requests() {
+    let closed = Arc::new(Closed(AtomicUsize::new(0)));
+
+    let builder = pipeline::ClientBuilder::new()
+        .observer(closed.clone())
+        .keepalive(Duration::from_millis(20), Duration::from_millis(50));
+
+    run_with(builder, |mock, service| {
+        mock.allow_write();
+
+        let pong = service.call(pipeline::Message::WithoutBody("ping"));
+        assert_eq!("ping", mock.next_write().unwrap_msg());
+
+        // The peer goes quiet
+        mock.allow_write();
+        match mock.next_write() {
+            pipeline::Frame::Ping => {}
+            frame => panic!("expected ping; actual={:?}", frame),
+        }
+
+        // The ping is not answered, the connection is closed and the pending
+        // request fails
+        mock.assert_drop();
+        assert_eq!(io::ErrorKind::BrokenPipe, pong.wait().unwrap_err().kind());
+
+        // The observer was set along with keepalive
+        assert_eq!(1, closed.0.load(Ordering::SeqCst));
+    });
+}
+
+/// Counts closed connections
+struct Closed(AtomicUsize);
+
+impl Observer for Closed {
+    fn connection_closed(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
 /// Setup a reactor running a pipeline::Client and a mock transport. Yields the
 /// mock transport handle to the function.
 fn run<F>(f: F) where F: FnOnce(TransportHandle, Client) {
+    run_with(pipeline::ClientBuilder::new(), f)
+}
+
+/// Like `run`, connecting the client with `builder`
+fn run_with<F>(builder: pipeline::ClientBuilder, f: F)
+    where F: FnOnce(TransportHandle, Client),
+{
     let _ = ::env_logger::init();
 
     let (tx, rx) = oneshot();
@@ -113,9 +163,9 @@ fn run<F>(f: F) where F: FnOnce(TransportHandle, Client) {
         let transport = new_transport.new_transport().unwrap();
         let transport = RefCell::new(Some(transport));
 
-        let service = pipeline::connect(&handle, move || {
-            Ok(transport.borrow_mut().take().unwrap())
-        }).unwrap();
+        let new_transport = move || Ok(transport.borrow_mut().take().unwrap());
+
+        let service = builder.connect(&handle, new_transport).unwrap();
         tx2.send((mock, service)).unwrap();
         lp.run(rx)
     });
@@ -1,3 +1,4 @@
+extern crate bytes;
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_proto;
@@ -10,13 +11,20 @@ extern crate env_logger;
 
 mod support;
 
-use futures::stream::{self, Stream, Receiver};
-use futures::{Future, failed, finished, oneshot};
+use bytes::{BlockBuf, MutBuf};
+use futures::stream::{self, Empty, Stream, Receiver};
+use futures::{task, Async, Future, Poll, failed, finished, lazy, oneshot};
 use support::mock;
+use tokio_proto::{duplex, Codec, CodecFramed, ParseBuf};
+use tokio_proto::metrics::Observer;
+use tokio_proto::request_context;
 use tokio_proto::pipeline::{self, Frame, Message};
-use tokio_core::reactor::Core;
+use tokio_core::io::{read_exact, write_all};
+use tokio_core::reactor::{Core, Handle};
 use std::io;
 use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use std::thread;
 
 // The message type is a static string for both the request and response
@@ -207,6 +215,28 @@ fn test_returning_error_from_service() {
     });
 }
 
+#[test]
+fn test_response_failing_to_serialize() {
+    let mut lp = Core::new().unwrap();
+    let (client_io, server_io) = duplex();
+
+    // Responses containing a newline cannot be serialized
+    let service = tokio_service::simple_service(|req: String| {
+        let resp: Message<String, Empty<(), io::Error>> = Message::WithoutBody(req.replace("_", "\n"));
+        finished(resp)
+    });
+
+    let server = pipeline::Server::new(service, CodecFramed::with_codec(server_io, Lines)).unwrap();
+    lp.handle().spawn(server.map_err(|_| ()));
+
+    let (client_io, _) = lp.run(write_all(client_io, b"hello_world\nhello\n")).unwrap();
+
+    // The first response is replaced with an error, without any of its bytes
+    // making it to the peer, and the second one is still delivered
+    let (_, out) = lp.run(read_exact(client_io, vec![0; 12])).unwrap();
+    assert_eq!(b"error\nhello\n", &out[..]);
+}
+
 #[test]
 fn test_reading_error_frame_from_transport() {
     let service = tokio_service::simple_service(move |_| {
@@ -421,6 +451,512 @@ fn channel<T>() -> (Arc<Mutex<mpsc::Sender<T>>>, mpsc::Receiver<T>) {
     (tx, rx)
 }
 
+#[test]
+fn test_reading_stops_at_max_in_flight() {
+    let (tx, rx) = channel();
+
+    let service = tokio_service::simple_service(move |_| {
+        let (c, fut) = oneshot();
+        tx.lock().unwrap().send(c).unwrap();
+        fut.then(|r| r.unwrap())
+    });
+
+    run_with(service, |server, _| server.set_max_in_flight(2), |mock| {
+        for _ in 0..3 { mock.allow_write() };
+
+        mock.send(msg("hello"));
+        mock.send(msg("hello"));
+        mock.send(msg("hello"));
+
+        let c1 = rx.recv().unwrap();
+        let c2 = rx.recv().unwrap();
+
+        // The third request is not read until a response is written
+        support::sleep_ms(20);
+        assert!(rx.try_recv().is_err());
+
+        c1.complete(Ok(Message::WithoutBody("one")));
+        assert_eq!("one", mock.next_write().unwrap_msg());
+
+        let c3 = rx.recv().unwrap();
+
+        c2.complete(Ok(Message::WithoutBody("two")));
+        c3.complete(Ok(Message::WithoutBody("three")));
+
+        assert_eq!("two", mock.next_write().unwrap_msg());
+        assert_eq!("three", mock.next_write().unwrap_msg());
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_only_notified_responses_are_polled() {
+    let (c, fut) = oneshot();
+    let fut = Mutex::new(Some(fut));
+
+    let polls = Arc::new(AtomicUsize::new(0));
+    let polls2 = polls.clone();
+
+    let service = tokio_service::simple_service(move |req| {
+        match fut.lock().unwrap().take() {
+            // The first response is delayed, counting how often it is polled
+            Some(fut) => {
+                let fut = fut.then(|r| r.unwrap());
+                CountPolls(fut, polls2.clone()).boxed()
+            }
+            None => finished(req).boxed(),
+        }
+    });
+
+    run(service, |mock| {
+        for _ in 0..3 { mock.allow_write() };
+
+        mock.send(msg("hello"));
+        support::sleep_ms(20);
+
+        // Processing other requests does not poll the delayed response again
+        mock.send(msg("two"));
+        support::sleep_ms(20);
+
+        mock.send(msg("three"));
+        support::sleep_ms(20);
+
+        assert_eq!(1, polls.load(Ordering::SeqCst));
+
+        c.complete(Ok(Message::WithoutBody("one")));
+
+        assert_eq!("one", mock.next_write().unwrap_msg());
+        assert_eq!("two", mock.next_write().unwrap_msg());
+        assert_eq!("three", mock.next_write().unwrap_msg());
+
+        assert_eq!(2, polls.load(Ordering::SeqCst));
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_repeated_notifications_poll_once() {
+    let (c, fut) = oneshot();
+    let fut = Mutex::new(Some(fut));
+
+    let polls = Arc::new(AtomicUsize::new(0));
+    let polls2 = polls.clone();
+
+    let service = tokio_service::simple_service(move |_| {
+        // Notifies the task several times, then waits for the response
+        let fut = fut.lock().unwrap().take().unwrap().then(|r| r.unwrap());
+        let fut = NotifyRepeatedly { inner: fut, notified: false };
+        CountPolls(fut, polls2.clone()).boxed()
+    });
+
+    run(service, |mock| {
+        mock.allow_write();
+        mock.send(msg("hello"));
+        support::sleep_ms(20);
+
+        // Polled once when dispatched, and once for all the notifications
+        assert_eq!(2, polls.load(Ordering::SeqCst));
+
+        c.complete(Ok(Message::WithoutBody("hello")));
+        assert_eq!("hello", mock.next_write().unwrap_msg());
+
+        assert_eq!(3, polls.load(Ordering::SeqCst));
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_observing_connection_events() {
+    let service = tokio_service::simple_service(|req| {
+        finished(req)
+    });
+
+    let counts = Arc::new(Counts::default());
+
+    let observer = counts.clone();
+
+    run_with(service, move |server, _| server.set_observer(observer), |mock| {
+        mock.allow_write();
+        mock.send(msg("hello"));
+        assert_eq!(mock.next_write().unwrap_msg(), "hello");
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+
+    assert_eq!(2, counts.frames_read.load(Ordering::SeqCst));
+    assert_eq!(1, counts.frames_written.load(Ordering::SeqCst));
+    assert_eq!(1, counts.requests.load(Ordering::SeqCst));
+    assert_eq!(1, counts.responses.load(Ordering::SeqCst));
+    assert_eq!(1, counts.closed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_request_context_and_trace_extraction() {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let seen2 = seen.clone();
+
+    let service = tokio_service::simple_service(move |req: Message<&'static str, Body>| {
+        let seen = seen2.clone();
+
+        // The context is available from `call`, and while the response
+        // future is polled
+        let cx = request_context().unwrap();
+        seen.lock().unwrap().push((cx.request_seq(), cx.trace_id().map(String::from)));
+
+        lazy(move || {
+            let cx = request_context().unwrap();
+            seen.lock().unwrap().push((cx.request_seq(), cx.trace_id().map(String::from)));
+            finished(req)
+        })
+    });
+
+    run_with(service, |server, _| {
+        server.set_trace_extractor(|req: &Message<Msg, Body>| {
+            match *req {
+                Message::WithoutBody(msg) if msg != "untraced" => Some(format!("trace-{}", msg)),
+                _ => None,
+            }
+        });
+    }, |mock| {
+        mock.allow_write();
+
+        mock.send(msg("hello"));
+        assert_eq!(mock.next_write().unwrap_msg(), "hello");
+
+        mock.send(msg("untraced"));
+        assert_eq!(mock.next_write().unwrap_msg(), "untraced");
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+
+    assert!(request_context().is_none());
+    assert_eq!(*seen.lock().unwrap(), vec![
+        (0, Some("trace-hello".to_string())),
+        (0, Some("trace-hello".to_string())),
+        (1, None),
+        (1, None),
+    ]);
+}
+
+#[test]
+fn test_keepalive_pings_idle_connection() {
+    let service = tokio_service::simple_service(|req| {
+        finished(req)
+    });
+
+    run_with(service, |server, handle| {
+        server.set_keepalive(Duration::from_millis(20), Duration::from_millis(50), handle);
+    }, |mock| {
+        mock.allow_write();
+        assert_ping(mock.next_write());
+
+        // The peer answers, the connection is pinged again once idle
+        mock.send(Frame::Pong);
+
+        mock.allow_write();
+        assert_ping(mock.next_write());
+
+        // The peer does not answer
+        mock.assert_drop();
+    });
+}
+
+#[test]
+fn test_keepalive_stops_once_done_reading() {
+    let (c, fut) = oneshot();
+    let fut = Mutex::new(Some(fut));
+
+    let service = tokio_service::simple_service(move |_| {
+        fut.lock().unwrap().take().unwrap().then(|r| r.unwrap())
+    });
+
+    run_with(service, |server, handle| {
+        server.set_keepalive(Duration::from_millis(20), Duration::from_millis(50), handle);
+    }, |mock| {
+        mock.send(msg("one"));
+        mock.send(Frame::Done);
+
+        // Answers are no longer read, the peer is not pinged while the
+        // response is delayed
+        mock.allow_write();
+        mock.assert_no_write(100);
+
+        c.complete(Ok(Message::WithoutBody("one")));
+        assert_eq!("one", mock.next_write().unwrap_msg());
+
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_answering_pings() {
+    let service = tokio_service::simple_service(|req| {
+        finished(req)
+    });
+
+    run(service, |mock| {
+        mock.send(Frame::Ping);
+
+        mock.allow_write();
+        match mock.next_write() {
+            Frame::Pong => {}
+            frame => panic!("expected pong; actual={:?}", frame),
+        }
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_answering_pings_after_response_body() {
+    let (tx, rx) = stream::channel::<u32, io::Error>();
+    let rx = Mutex::new(Some(rx));
+
+    let service = tokio_service::simple_service(move |_| {
+        finished(Message::WithBody("hi2u", rx.lock().unwrap().take().unwrap()))
+    });
+
+    run(service, |mock| {
+        mock.allow_write();
+        mock.send(msg("omg"));
+
+        assert_eq!(mock.next_write().unwrap_msg(), "hi2u");
+
+        // The pong does not interrupt the response body
+        mock.send(Frame::Ping);
+        mock.allow_write();
+        mock.assert_no_write(20);
+
+        let tx = tx.send(Ok(1)).wait().ok().unwrap();
+        assert_eq!(Some(1), mock.next_write().unwrap_body());
+
+        drop(tx);
+        mock.allow_write();
+        assert_eq!(None, mock.next_write().unwrap_body());
+
+        mock.allow_write();
+        match mock.next_write() {
+            Frame::Pong => {}
+            frame => panic!("expected pong; actual={:?}", frame),
+        }
+
+        mock.send(Frame::Done);
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_draining_connection() {
+    let (c, fut) = oneshot();
+    let fut = Mutex::new(Some(fut));
+
+    // Only the first request is dispatched
+    let service = tokio_service::simple_service(move |req| {
+        assert_eq!(req, "one");
+        fut.lock().unwrap().take().unwrap().then(|r| r.unwrap())
+    });
+
+    let (tx, rx) = mpsc::channel();
+
+    run_with(service, move |server, _| {
+        server.set_goaway(true);
+        tx.send(server.drain_handle()).unwrap();
+    }, |mock| {
+        let drain = rx.recv().unwrap();
+
+        mock.send(msg("one"));
+        support::sleep_ms(20);
+
+        drain.drain();
+        assert!(drain.is_draining());
+
+        mock.allow_write();
+        match mock.next_write() {
+            Frame::GoAway => {}
+            frame => panic!("expected goaway; actual={:?}", frame),
+        }
+
+        // Requests are no longer read, the connection is kept open until the
+        // in-flight response is written
+        mock.send(msg("two"));
+        mock.assert_no_write(20);
+
+        c.complete(Ok(Message::WithoutBody("one")));
+
+        mock.allow_write();
+        assert_eq!("one", mock.next_write().unwrap_msg());
+
+        mock.allow_and_assert_drop();
+    });
+}
+
+#[test]
+fn test_draining_connection_without_goaway() {
+    let (c, fut) = oneshot();
+    let fut = Mutex::new(Some(fut));
+
+    let service = tokio_service::simple_service(move |_| {
+        fut.lock().unwrap().take().unwrap().then(|r| r.unwrap())
+    });
+
+    let (tx, rx) = mpsc::channel();
+
+    run_with(service, move |server, _| tx.send(server.drain_handle()).unwrap(), |mock| {
+        let drain = rx.recv().unwrap();
+
+        mock.send(msg("one"));
+        support::sleep_ms(20);
+
+        // Nothing is written until the in-flight response completes
+        drain.drain();
+        mock.allow_write();
+        mock.assert_no_write(20);
+
+        c.complete(Ok(Message::WithoutBody("one")));
+        assert_eq!("one", mock.next_write().unwrap_msg());
+
+        mock.allow_and_assert_drop();
+    });
+}
+
+fn assert_ping(frame: InFrame) {
+    match frame {
+        Frame::Ping => {}
+        frame => panic!("expected ping; actual={:?}", frame),
+    }
+}
+
+/// Counts connection events
+#[derive(Default)]
+struct Counts {
+    frames_read: AtomicUsize,
+    frames_written: AtomicUsize,
+    requests: AtomicUsize,
+    responses: AtomicUsize,
+    closed: AtomicUsize,
+}
+
+impl Observer for Counts {
+    fn frame_read(&self) {
+        self.frames_read.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn frame_written(&self) {
+        self.frames_written.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn request_dispatched(&self, in_flight: usize) {
+        assert_eq!(1, in_flight);
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn response_completed(&self, _: Duration) {
+        self.responses.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn connection_closed(&self) {
+        self.closed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Notifies the task three times on the first poll, then completes with the
+/// inner future
+struct NotifyRepeatedly<F> {
+    inner: F,
+    notified: bool,
+}
+
+impl<F: Future> Future for NotifyRepeatedly<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        if self.notified {
+            return self.inner.poll();
+        }
+
+        let task = task::park();
+        for _ in 0..3 { task.unpark() };
+
+        self.notified = true;
+        Ok(Async::NotReady)
+    }
+}
+
+/// Counts the number of times the future is polled
+struct CountPolls<F>(F, Arc<AtomicUsize>);
+
+impl<F: Future> Future for CountPolls<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        self.0.poll()
+    }
+}
+
+/// Pipeline frames as `\n` terminated lines, with errors written as `error`
+struct Lines;
+
+impl Codec for Lines {
+    type Out = Frame<String, (), io::Error>;
+    type In = Frame<String, (), io::Error>;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        let n = match buf.find_byte(b'\n') {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let mut line = vec![0; n + 1];
+        buf.peek_slice(&mut line);
+        buf.drop(n + 1);
+
+        line.pop();
+
+        match String::from_utf8(line) {
+            Ok(s) => Ok(Some(Frame::Message(s))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8")),
+        }
+    }
+
+    fn done(&mut self, _: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        Ok(Some(Frame::Done))
+    }
+
+    fn serialize(&mut self, frame: Self::In, buf: &mut BlockBuf) -> io::Result<()> {
+        match frame {
+            Frame::Message(line) => {
+                buf.write_slice(line.as_bytes());
+
+                // Validated after writing, the partial frame is discarded
+                if line.contains('\n') {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "line contains a newline"));
+                }
+
+                buf.write_slice(b"\n");
+            }
+            Frame::Error(_) => {
+                buf.write_slice(b"error\n");
+            }
+            Frame::Done => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported frame")),
+        }
+
+        Ok(())
+    }
+}
+
 fn msg(msg: Msg) -> OutFrame {
     Frame::Message(Message::WithoutBody(msg))
 }
@@ -440,6 +976,19 @@ fn run<S, F>(service: S, f: F)
                                        Error = io::Error> + Send + 'static,
           S::Future: Send + 'static,
           F: FnOnce(mock::TransportHandle<InFrame, OutFrame>),
+{
+    run_with(service, |_, _| {}, f)
+}
+
+fn run_with<S, C, F>(service: S, configure: C, f: F)
+    where S: pipeline::ServerService<Request = pipeline::Message<Msg, Body>,
+                                    Response = Msg,
+                                        Body = u32,
+                                  BodyStream = Body,
+                                       Error = io::Error> + Send + 'static,
+          S::Future: Send + 'static,
+          C: FnOnce(&mut pipeline::Server<S, mock::Transport<InFrame, OutFrame>>, &Handle) + Send + 'static,
+          F: FnOnce(mock::TransportHandle<InFrame, OutFrame>),
 {
     drop(::env_logger::init());
     let (tx, rx) = oneshot();
@@ -451,7 +1000,8 @@ fn run<S, F>(service: S, f: F)
         let (mock, new_transport) = mock::transport::<InFrame, OutFrame>(handle.clone());
 
         let transport = new_transport.new_transport().unwrap();
-        let dispatch = pipeline::Server::new(service, transport).unwrap();
+        let mut dispatch = pipeline::Server::new(service, transport).unwrap();
+        configure(&mut dispatch, &handle);
         handle.spawn(dispatch.map_err(|e| error!("error: {}", e)));
         tx2.send(mock).unwrap();
         lp.run(rx)
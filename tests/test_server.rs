@@ -1,6 +1,8 @@
+extern crate bytes;
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_proto;
+extern crate tokio_service;
 extern crate rand;
 
 #[macro_use]
@@ -9,11 +11,15 @@ extern crate env_logger;
 
 mod support;
 
+use bytes::{BlockBuf, MutBuf};
 use futures::{oneshot, Future, Poll, Async};
-use tokio_proto::server;
+use futures::stream::Empty;
+use tokio_proto::{pipeline, server, Codec, CodecFramed, ParseBuf, Peekable};
+use tokio_proto::pipeline::{Frame, Message};
+use tokio_core::io::{read_to_end, write_all};
 use tokio_core::reactor::Core;
-use std::io;
-use std::net::{SocketAddr, TcpStream};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::sync::mpsc;
 use std::thread;
 
@@ -53,3 +59,217 @@ fn test_accepting_multiple_sockets() {
     tx.complete(());
     t.join().unwrap().unwrap();
 }
+
+#[test]
+fn test_sniffing_protocols() {
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let (tx2, rx2) = oneshot();
+
+        let sniff = server::Sniff::new()
+            .protocol(b"RPC1", |socket| Ok(echo("rpc", socket)))
+            .protocol(b"PING", |socket| Ok(echo("ping", socket)))
+            .fallback(|socket| Ok(echo("admin", socket)));
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let srv = server::listen(&lp.handle(), addr, sniff).unwrap();
+
+        tx.send((tx2, *srv.local_addr())).unwrap();
+        lp.run(rx2)
+    });
+
+    let (tx, addr) = rx.recv().unwrap();
+
+    assert_eq!("rpc:RPC1hello", request(&addr, b"RPC1hello"));
+    assert_eq!("ping:PING", request(&addr, b"PING"));
+    assert_eq!("admin:stats", request(&addr, b"stats"));
+    // Closed before the magic bytes were complete
+    assert_eq!("admin:RPC", request(&addr, b"RPC"));
+
+    tx.complete(());
+    t.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_listing_connections() {
+    let registry = server::Registry::new();
+    let registry2 = registry.clone();
+
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let (tx2, rx2) = oneshot();
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let srv = server::listen(&lp.handle(), addr, move |socket| {
+            let registry = registry2.clone();
+
+            // The server is created later by the connection's task, as it
+            // would be after a TLS handshake
+            let task = futures::finished::<_, io::Error>(socket).and_then(move |socket| {
+                // Requests are never responded to
+                let service = tokio_service::simple_service(|_| {
+                    futures::empty::<Message<String, Empty<(), io::Error>>, io::Error>()
+                });
+
+                let mut server = try!(pipeline::Server::new(service, CodecFramed::with_codec(socket, Lines)));
+                server.set_registry(&registry);
+                Ok(server)
+            });
+
+            Ok(task.flatten())
+        }).unwrap();
+
+        tx.send((tx2, srv)).unwrap();
+        lp.run(rx2)
+    });
+
+    let (tx, srv) = rx.recv().unwrap();
+
+    assert!(registry.connections().is_empty());
+
+    let mut socket = TcpStream::connect(srv.local_addr()).unwrap();
+    socket.write_all(b"one\ntwo\n").unwrap();
+
+    // Wait for the server to read both requests
+    let mut connections = vec![];
+
+    for _ in 0..100 {
+        connections = registry.connections();
+
+        if connections.len() == 1 && connections[0].in_flight == 2 {
+            break;
+        }
+
+        support::sleep_ms(10);
+    }
+
+    assert_eq!(1, connections.len());
+    assert_eq!(2, connections[0].in_flight);
+    assert!(!connections[0].in_body);
+    assert!(!connections[0].out_body);
+    assert_eq!(0, connections[0].dispatch_deque);
+    assert!(connections[0].is_flushed);
+
+    tx.complete(());
+    t.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_draining_connection() {
+    let registry = server::Registry::new();
+    let registry2 = registry.clone();
+
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let (tx2, rx2) = oneshot();
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let srv = server::listen(&lp.handle(), addr, move |socket| {
+            let service = tokio_service::simple_service(|req: Message<String, Empty<(), io::Error>>| {
+                futures::finished::<_, io::Error>(req)
+            });
+
+            let mut server = try!(pipeline::Server::new(service, CodecFramed::with_codec(socket, Lines)));
+            server.set_goaway(true);
+            server.set_registry(&registry2);
+            Ok(server)
+        }).unwrap();
+
+        tx.send((tx2, srv)).unwrap();
+        lp.run(rx2)
+    });
+
+    let (tx, srv) = rx.recv().unwrap();
+
+    let mut socket = TcpStream::connect(srv.local_addr()).unwrap();
+    socket.write_all(b"one\n").unwrap();
+
+    let mut buf = [0; 4];
+    socket.read_exact(&mut buf).unwrap();
+    assert_eq!(b"one\n", &buf);
+
+    let connection_id = registry.connections()[0].connection_id;
+    assert!(registry.drain_connection(connection_id));
+
+    // The server tells the client to go away, then closes the connection
+    let mut resp = String::new();
+    socket.read_to_string(&mut resp).unwrap();
+    assert_eq!("goaway\n", resp);
+
+    for _ in 0..100 {
+        if registry.connections().is_empty() {
+            break;
+        }
+
+        support::sleep_ms(10);
+    }
+
+    assert!(!registry.drain_connection(connection_id));
+
+    tx.complete(());
+    t.join().unwrap().unwrap();
+}
+
+// Responds with the name of the protocol followed by everything received
+fn echo(name: &'static str, socket: Peekable<tokio_core::net::TcpStream>)
+        -> Box<Future<Item=(), Error=io::Error>> {
+    Box::new(read_to_end(socket, vec![]).and_then(move |(socket, buf)| {
+        let mut out = format!("{}:", name).into_bytes();
+        out.extend_from_slice(&buf);
+        write_all(socket, out)
+    }).map(|_| ()))
+}
+
+fn request(addr: &SocketAddr, req: &[u8]) -> String {
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.write_all(req).unwrap();
+    socket.shutdown(Shutdown::Write).unwrap();
+
+    let mut resp = String::new();
+    socket.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+/// Pipeline frames as `\n` terminated lines
+struct Lines;
+
+impl Codec for Lines {
+    type Out = Frame<String, (), io::Error>;
+    type In = Frame<String, (), io::Error>;
+
+    fn parse(&mut self, buf: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        let n = match buf.find_byte(b'\n') {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let mut line = vec![0; n + 1];
+        buf.peek_slice(&mut line);
+        buf.drop(n + 1);
+        line.pop();
+
+        String::from_utf8(line)
+            .map(|line| Some(Frame::Message(line)))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8"))
+    }
+
+    fn done(&mut self, _: &mut BlockBuf) -> io::Result<Option<Self::Out>> {
+        Ok(Some(Frame::Done))
+    }
+
+    fn serialize(&mut self, frame: Self::In, buf: &mut BlockBuf) -> io::Result<()> {
+        match frame {
+            Frame::Message(line) => {
+                buf.write_slice(line.as_bytes());
+                buf.write_slice(b"\n");
+            }
+            Frame::GoAway => buf.write_slice(b"goaway\n"),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}